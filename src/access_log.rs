@@ -0,0 +1,75 @@
+//! One log line per request, for grepping without a tracing backend
+
+use std::{sync::Arc, time::Instant};
+
+use axum::{body::BoxBody, extract::State, http::Request, middleware::Next, response::Response};
+use tracing::info;
+
+use crate::geo::Geolocator;
+
+pub struct AccessLog {
+    /// Paths that aren't logged, like health checks and metrics scrapes,
+    /// which would drown everything else
+    excluded: Vec<String>,
+    /// To name the client's country
+    locat: Option<Arc<Geolocator>>,
+}
+
+impl AccessLog {
+    pub fn new(excluded: Vec<String>, locat: Option<Arc<Geolocator>>) -> Self {
+        Self { excluded, locat }
+    }
+
+    fn is_excluded(&self, path: &str) -> bool {
+        self.excluded.iter().any(|excluded| excluded == path)
+    }
+}
+
+/// Logs every request that isn't excluded once it's been answered, with
+/// structured fields
+pub async fn log<B>(
+    State(access_log): State<Arc<AccessLog>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response<BoxBody> {
+    if access_log.is_excluded(req.uri().path()) {
+        return next.run(req).await;
+    }
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let client_ip = crate::get_client_addr(req.headers());
+    let country = access_log
+        .locat
+        .as_deref()
+        .zip(client_ip)
+        .and_then(|(locat, addr)| locat.iso_code(addr));
+
+    let start = Instant::now();
+    let res = next.run(req).await;
+    info!(
+        %method,
+        path,
+        status = res.status().as_u16(),
+        client_ip = client_ip.map(display),
+        country,
+        duration_ms = start.elapsed().as_millis() as u64,
+        request_id = crate::request_id::current(),
+        "{method} {path} {}",
+        res.status().as_u16(),
+    );
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_excluded() {
+        let access_log = AccessLog::new(vec!["/healthz".into(), "/metrics".into()], None);
+        assert!(access_log.is_excluded("/healthz"));
+        assert!(access_log.is_excluded("/metrics"));
+        assert!(!access_log.is_excluded("/healthz/deep"));
+        assert!(!access_log.is_excluded("/"));
+    }
+}
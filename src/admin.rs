@@ -0,0 +1,82 @@
+//! Diagnostic routes, only for whoever has `$ADMIN_TOKEN`
+
+use std::sync::Arc;
+
+use axum::{
+    body::BoxBody,
+    extract::State,
+    http::{header, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::error::{ApiError, ErrorCode};
+
+/// When set, admin routes want `Authorization: Bearer <token>`. When it's
+/// not, they're closed to everyone.
+pub struct AdminToken(Option<String>);
+
+impl AdminToken {
+    pub fn new(token: Option<String>) -> Self {
+        Self(token.filter(|token| !token.is_empty()))
+    }
+
+    fn allows(&self, authorization: Option<&str>) -> bool {
+        let Some(expected) = &self.0 else {
+            return false;
+        };
+        let Some(token) = authorization.and_then(|auth| auth.strip_prefix("Bearer ")) else {
+            return false;
+        };
+        constant_time_eq(token.trim().as_bytes(), expected.as_bytes())
+    }
+}
+
+/// Doesn't stop at the first differing byte, so response times don't give
+/// the token away
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Answers 401 unless the request has the token, so every admin route stays
+/// closed until `$ADMIN_TOKEN` is set. That includes lookups like
+/// `/geoip/:ip`, which would otherwise let anyone query the database.
+pub async fn require_configured_token<B>(
+    State(token): State<Arc<AdminToken>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response<BoxBody> {
+    let authorization = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok());
+    if !token.allows(authorization) {
+        return ApiError {
+            code: ErrorCode::Unauthorized,
+            json: true,
+        }
+        .into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        // no token, no way in
+        let unset = AdminToken::new(None);
+        assert!(!unset.allows(None));
+        assert!(!unset.allows(Some("Bearer ")));
+        assert!(!AdminToken::new(Some("".into())).allows(Some("Bearer ")));
+
+        let token = AdminToken::new(Some("hunter2".into()));
+        assert!(token.allows(Some("Bearer hunter2")));
+        assert!(!token.allows(None));
+        assert!(!token.allows(Some("hunter2")));
+        assert!(!token.allows(Some("Bearer hunter")));
+        assert!(!token.allows(Some("Bearer hunter22")));
+    }
+}
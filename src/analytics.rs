@@ -0,0 +1,638 @@
+//! Where visits are counted: locat's SQLite DB, or memory for tests and
+//! deployments that don't need the counts to survive a restart
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    os::raw::c_int,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use opentelemetry::{trace::get_active_span, KeyValue};
+use rusqlite::{ffi, params, Connection, ErrorCode, TransactionBehavior};
+use tracing::{debug, info, warn};
+
+#[async_trait::async_trait]
+pub trait AnalyticsStore: Send + Sync {
+    /// Counts a visit from `addr`, which is in `country`
+    async fn record_visit(&self, addr: IpAddr, country: &str) -> color_eyre::Result<()>;
+
+    /// Returns a list of country codes with their number of visits, in no
+    /// particular order
+    async fn get_analytics(&self) -> color_eyre::Result<Vec<(String, u64)>>;
+
+    /// Like [Self::get_analytics], counting only visits from `since` on, as
+    /// far back as the store keeps their times
+    async fn get_analytics_since(
+        &self,
+        since: SystemTime,
+    ) -> color_eyre::Result<Vec<(String, u64)>>;
+
+    /// Forgets every visit
+    async fn clear(&self) -> color_eyre::Result<()>;
+}
+
+/// Which [AnalyticsStore], from `$ANALYTICS_BACKEND`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnalyticsBackend {
+    /// The file at `$ANALYTICS_DB`
+    Sqlite,
+    Memory,
+}
+
+impl FromStr for AnalyticsBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sqlite" => Ok(Self::Sqlite),
+            "memory" => Ok(Self::Memory),
+            _ => Err("expected sqlite or memory".into()),
+        }
+    }
+}
+
+impl std::fmt::Display for AnalyticsBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Sqlite => "sqlite",
+            Self::Memory => "memory",
+        })
+    }
+}
+
+/// The SQLite DB at `$ANALYTICS_DB`, in locat's schema: a count per country
+/// in an `analytics` table. locat only counts a visit as a side effect of
+/// its own lookup, and keeps any error to itself, so it's written through a
+/// connection of our own instead, where failures can be retried.
+///
+/// Each visit also gets a row in `visits`, with its time, for `since`. That
+/// table came later: visits counted before it was there are in the all-time
+/// counts only.
+pub struct SqliteStore {
+    path: String,
+    /// Held for every query, so it's also what keeps writers apart
+    conn: tokio::sync::Mutex<Connection>,
+    /// From `$ANALYTICS_RECONNECT_RETRIES`, see [reopen]
+    reconnect_retries: u32,
+}
+
+impl SqliteStore {
+    /// Opens the DB at `path`, creating or migrating its tables as needed
+    pub fn open(path: String, reconnect_retries: u32) -> rusqlite::Result<Self> {
+        Ok(Self {
+            conn: tokio::sync::Mutex::new(open(&path)?),
+            path,
+            reconnect_retries,
+        })
+    }
+
+    /// Runs `f` once the DB isn't locked, see [retry_locked]. If the
+    /// connection itself is broken, swaps in a fresh one and tries again,
+    /// just once: a fresh connection that fails too won't do better.
+    async fn run<T>(
+        &self,
+        mut f: impl FnMut(&mut Connection) -> rusqlite::Result<T> + Send,
+    ) -> rusqlite::Result<T> {
+        let mut conn = self.conn.lock().await;
+        match retry_locked(|| f(&mut conn)).await {
+            Err(e) if is_connection_error(&e) => {
+                warn!("Analytics DB failed, reconnecting: {e}");
+                let Some(fresh) = reopen(self.reconnect_retries, || open(&self.path)).await else {
+                    return Err(e);
+                };
+                *conn = fresh;
+                retry_locked(|| f(&mut conn)).await
+            }
+            res => res,
+        }
+    }
+}
+
+/// Opens a connection to the DB at `path`. The `analytics` table is
+/// locat's, down to the column names, so a DB written by locat keeps its
+/// counts and locat could still read this one. Ours come after it, in
+/// [MIGRATIONS].
+fn open(path: &str) -> rusqlite::Result<Connection> {
+    let mut conn = Connection::open(path)?;
+    // immediate, so two processes starting at once don't both migrate
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS analytics (
+            iso_code TEXT PRIMARY KEY,
+            count INTEGER NOT NULL
+        )",
+    )?;
+    let version: u32 = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i as u32 + 1)?;
+    }
+    tx.commit()?;
+    Ok(conn)
+}
+
+/// Run in order on DBs whose `user_version` is below their position plus
+/// one. Only ever append: DBs out there already ran the ones before.
+const MIGRATIONS: &[&str] = &["CREATE TABLE visits (
+        iso_code TEXT NOT NULL,
+        visited_at INTEGER NOT NULL
+    );
+    CREATE INDEX visits_visited_at ON visits (visited_at);"];
+
+/// Seconds since the Unix epoch, which is how visit times are stored
+fn unix_secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() as i64)
+}
+
+#[async_trait::async_trait]
+impl AnalyticsStore for SqliteStore {
+    async fn record_visit(&self, _addr: IpAddr, country: &str) -> color_eyre::Result<()> {
+        let now = unix_secs(SystemTime::now());
+        self.run(|conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO analytics (iso_code, count) VALUES (?1, 1)
+                ON CONFLICT (iso_code) DO UPDATE SET count = count + 1",
+                [country],
+            )?;
+            tx.execute(
+                "INSERT INTO visits (iso_code, visited_at) VALUES (?1, ?2)",
+                params![country, now],
+            )?;
+            tx.commit()
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn get_analytics(&self) -> color_eyre::Result<Vec<(String, u64)>> {
+        Ok(self
+            .run(|conn| {
+                conn.prepare_cached("SELECT iso_code, count FROM analytics")?
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect()
+            })
+            .await?)
+    }
+
+    async fn get_analytics_since(
+        &self,
+        since: SystemTime,
+    ) -> color_eyre::Result<Vec<(String, u64)>> {
+        let since = unix_secs(since);
+        Ok(self
+            .run(|conn| {
+                conn.prepare_cached(
+                    "SELECT iso_code, COUNT(*) FROM visits
+                    WHERE visited_at >= ?1 GROUP BY iso_code",
+                )?
+                .query_map([since], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect()
+            })
+            .await?)
+    }
+
+    async fn clear(&self) -> color_eyre::Result<()> {
+        self.run(|conn| {
+            let tx = conn.transaction()?;
+            tx.execute_batch("DELETE FROM analytics; DELETE FROM visits;")?;
+            tx.commit()
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+/// How many more tries a locked DB gets, the delay growing by
+/// [LOCK_RETRY_DELAY] each time
+const LOCK_RETRIES: u32 = 3;
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Whether `e` is SQLite's `SQLITE_BUSY` or `SQLITE_LOCKED`
+fn is_locked(e: &rusqlite::Error) -> bool {
+    matches!(
+        e.sqlite_error_code(),
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+    )
+}
+
+/// Runs `f` until the DB isn't locked, or [LOCK_RETRIES] retries later.
+/// Other writers only hold the lock for a moment, a failure that's still
+/// locked after that turns into a 503 like any other. Retries are counted on
+/// the current span.
+async fn retry_locked<T>(mut f: impl FnMut() -> rusqlite::Result<T>) -> rusqlite::Result<T> {
+    let mut retries = 0;
+    loop {
+        match f() {
+            Err(e) if retries < LOCK_RETRIES && is_locked(&e) => {
+                retries += 1;
+                debug!("Analytics DB is locked, retry {retries}: {e}");
+                tokio::time::sleep(LOCK_RETRY_DELAY * retries).await;
+            }
+            res => {
+                if retries > 0 {
+                    get_active_span(|span| {
+                        span.set_attribute(KeyValue::new("analytics_lock_retries", retries as i64))
+                    });
+                }
+                return res;
+            }
+        }
+    }
+}
+
+/// How long before the first reconnect, doubling for each one after that
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// SQLite's extended code for a DB file that was moved or deleted while
+/// open, which the bindings don't name
+const SQLITE_READONLY_DBMOVED: c_int = ffi::SQLITE_READONLY | (4 << 8);
+
+/// Whether `e` is something a new connection could fix: the file failing to
+/// read or write, or being gone or replaced. Not a bad query or a broken
+/// constraint, which would fail the same way again.
+fn is_connection_error(e: &rusqlite::Error) -> bool {
+    let Some(e) = e.sqlite_error() else {
+        return false;
+    };
+    matches!(
+        e.code,
+        ErrorCode::SystemIoFailure | ErrorCode::CannotOpen | ErrorCode::NotADatabase
+    ) || e.extended_code == SQLITE_READONLY_DBMOVED
+}
+
+/// Runs `open` up to `retries` times, backing off exponentially, until it
+/// succeeds. A reconnect is logged and counted on the current span.
+async fn reopen<T, E>(retries: u32, mut open: impl FnMut() -> Result<T, E>) -> Option<T>
+where
+    E: std::fmt::Display,
+{
+    for attempt in 1..=retries {
+        let delay = RECONNECT_DELAY.saturating_mul(2u32.saturating_pow(attempt - 1));
+        tokio::time::sleep(delay.min(MAX_RECONNECT_DELAY)).await;
+        match open() {
+            Ok(conn) => {
+                info!("Reconnected to the analytics DB, attempt {attempt}");
+                get_active_span(|span| {
+                    span.set_attribute(KeyValue::new("analytics_reconnects", attempt as i64))
+                });
+                return Some(conn);
+            }
+            Err(e) => warn!("Could not reconnect to the analytics DB, attempt {attempt}: {e}"),
+        }
+    }
+    None
+}
+
+/// Visit counts by country, gone when the process exits. Their times are
+/// only kept to the minute, for [MEMORY_RETENTION], so memory stays bounded
+/// however many visits come in: a `since` further back only counts that
+/// far, and visits in the minute `since` falls in aren't counted.
+#[derive(Default)]
+pub struct MemoryStore {
+    visits: Mutex<MemoryVisits>,
+}
+
+/// How long a [MemoryStore] keeps visit times
+const MEMORY_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Default)]
+struct MemoryVisits {
+    /// All-time, never pruned
+    totals: HashMap<String, u64>,
+    /// Counts by minute since the Unix epoch, oldest first, for the minutes
+    /// that had visits
+    minutes: VecDeque<(u64, HashMap<String, u64>)>,
+}
+
+impl MemoryVisits {
+    fn record(&mut self, country: &str, now: SystemTime) {
+        *self.totals.entry(country.to_owned()).or_default() += 1;
+
+        // a clock going back stays in the last minute, to keep them in order
+        let last = self.minutes.back().map(|(minute, _)| *minute);
+        let minute = (unix_secs(now) as u64 / 60).max(last.unwrap_or(0));
+        if last != Some(minute) {
+            self.minutes.push_back((minute, HashMap::new()));
+        }
+        let (_, counts) = self.minutes.back_mut().unwrap();
+        *counts.entry(country.to_owned()).or_default() += 1;
+
+        let oldest = minute.saturating_sub(MEMORY_RETENTION.as_secs() / 60);
+        while matches!(self.minutes.front(), Some((minute, _)) if *minute < oldest) {
+            self.minutes.pop_front();
+        }
+    }
+
+    fn since(&self, since: SystemTime) -> Vec<(String, u64)> {
+        // the first whole minute from `since` on
+        let since = since.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since.as_secs() + u64::from(since.subsec_nanos() > 0);
+        let first = (secs + 59) / 60;
+
+        let mut totals = HashMap::<String, u64>::new();
+        for (_, counts) in self.minutes.iter().filter(|(minute, _)| *minute >= first) {
+            for (country, count) in counts {
+                *totals.entry(country.clone()).or_default() += count;
+            }
+        }
+        totals.into_iter().collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl AnalyticsStore for MemoryStore {
+    async fn record_visit(&self, _addr: IpAddr, country: &str) -> color_eyre::Result<()> {
+        self.visits
+            .lock()
+            .unwrap()
+            .record(country, SystemTime::now());
+        Ok(())
+    }
+
+    async fn get_analytics(&self) -> color_eyre::Result<Vec<(String, u64)>> {
+        let visits = self.visits.lock().unwrap();
+        Ok(visits
+            .totals
+            .iter()
+            .map(|(country, count)| (country.clone(), *count))
+            .collect())
+    }
+
+    async fn get_analytics_since(
+        &self,
+        since: SystemTime,
+    ) -> color_eyre::Result<Vec<(String, u64)>> {
+        Ok(self.visits.lock().unwrap().since(since))
+    }
+
+    async fn clear(&self) -> color_eyre::Result<()> {
+        *self.visits.lock().unwrap() = MemoryVisits::default();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory() {
+        let store = MemoryStore::default();
+        assert!(store.get_analytics().await.unwrap().is_empty());
+
+        let addr = IpAddr::from([1, 2, 3, 4]);
+        store.record_visit(addr, "FR").await.unwrap();
+        store.record_visit(addr, "FR").await.unwrap();
+        store.record_visit(addr, "US").await.unwrap();
+        let mut analytics = store.get_analytics().await.unwrap();
+        analytics.sort();
+        assert_eq!(analytics, [("FR".to_owned(), 2), ("US".to_owned(), 1)]);
+
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        let mut recent = store.get_analytics_since(hour_ago).await.unwrap();
+        recent.sort();
+        assert_eq!(recent, analytics);
+        let later = SystemTime::now() + Duration::from_secs(1);
+        assert!(store.get_analytics_since(later).await.unwrap().is_empty());
+
+        store.clear().await.unwrap();
+        assert!(store.get_analytics().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_memory_retention() {
+        let start = humantime::parse_rfc3339("2024-01-01T00:00:30Z").unwrap();
+        let at = |secs| start + Duration::from_secs(secs);
+        let since = |visits: &MemoryVisits, time| {
+            let mut counts = visits.since(time);
+            counts.sort();
+            counts
+        };
+        let mut visits = MemoryVisits::default();
+        visits.record("FR", start);
+        visits.record("FR", at(10));
+        visits.record("US", at(40));
+        assert_eq!(visits.minutes.len(), 2);
+
+        // to the whole minute, from the first one starting at `since` on
+        assert_eq!(
+            since(&visits, at(0) - Duration::from_secs(30)),
+            [("FR".to_owned(), 2), ("US".to_owned(), 1)]
+        );
+        assert_eq!(since(&visits, start), [("US".to_owned(), 1)]);
+        assert!(since(&visits, at(31)).is_empty());
+
+        // a week later, the first visits are only in the totals
+        let week = MEMORY_RETENTION.as_secs();
+        visits.record("DE", at(week + 30));
+        assert_eq!(visits.minutes.len(), 2);
+        assert_eq!(
+            since(&visits, UNIX_EPOCH),
+            [("DE".to_owned(), 1), ("US".to_owned(), 1)]
+        );
+        assert_eq!(visits.totals.values().sum::<u64>(), 4);
+
+        // nor does the clock going back put a minute out of order
+        visits.record("DE", start);
+        assert_eq!(visits.minutes.len(), 2);
+        assert_eq!(since(&visits, at(week)), [("DE".to_owned(), 2)]);
+    }
+
+    /// A fresh path under the temp dir, for a DB named `name`
+    fn test_db_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("catscii-{}-{name}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn test_retry_locked() {
+        let path = test_db_path("locked");
+        let holder = open(&path).unwrap();
+        let conn = open(&path).unwrap();
+        conn.busy_timeout(Duration::ZERO).unwrap();
+        let insert = || conn.execute("INSERT INTO analytics VALUES ('FR', 1)", []);
+
+        holder.execute_batch("BEGIN EXCLUSIVE").unwrap();
+        let tries = std::cell::Cell::new(0);
+        let res = retry_locked(|| {
+            tries.set(tries.get() + 1);
+            if tries.get() == 3 {
+                holder.execute_batch("COMMIT").unwrap();
+            }
+            insert()
+        })
+        .await;
+        assert_eq!(res, Ok(1));
+        assert_eq!(tries.get(), 3);
+
+        // other errors aren't retried, locks not forever
+        tries.set(0);
+        let res = retry_locked(|| {
+            tries.set(tries.get() + 1);
+            insert()
+        })
+        .await;
+        assert!(!is_locked(&res.unwrap_err()));
+        assert_eq!(tries.get(), 1);
+
+        holder.execute_batch("BEGIN EXCLUSIVE").unwrap();
+        tries.set(0);
+        let res = retry_locked(|| {
+            tries.set(tries.get() + 1);
+            insert()
+        })
+        .await;
+        assert!(is_locked(&res.unwrap_err()));
+        assert_eq!(tries.get(), 1 + LOCK_RETRIES);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reopen() {
+        let path = test_db_path("not-a-db");
+        std::fs::write(&path, "not a database, just some text ".repeat(10)).unwrap();
+        let e = open(&path).unwrap_err();
+        assert_eq!(e.sqlite_error_code(), Some(ErrorCode::NotADatabase));
+        assert!(is_connection_error(&e));
+        std::fs::remove_file(path).unwrap();
+
+        let e = open("/nonexistent/analytics.db").unwrap_err();
+        assert!(is_connection_error(&e));
+
+        // the query or the data being wrong isn't the connection's fault
+        let conn = Connection::open_in_memory().unwrap();
+        let e = conn.execute("SELECT * FROM analytics", []).unwrap_err();
+        assert!(!is_connection_error(&e));
+        let conn = open(":memory:").unwrap();
+        conn.execute("INSERT INTO analytics VALUES ('FR', 1)", [])
+            .unwrap();
+        let e = conn
+            .execute("INSERT INTO analytics VALUES ('FR', 1)", [])
+            .unwrap_err();
+        assert!(!is_connection_error(&e));
+
+        let tries = std::cell::Cell::new(0);
+        let res = reopen(3, || {
+            tries.set(tries.get() + 1);
+            match tries.get() {
+                1 | 2 => Err("unable to open database file"),
+                _ => Ok(42),
+            }
+        })
+        .await;
+        assert_eq!(res, Some(42));
+        assert_eq!(tries.get(), 3);
+
+        tries.set(0);
+        let res: Option<()> = reopen(2, || {
+            tries.set(tries.get() + 1);
+            Err("unable to open database file")
+        })
+        .await;
+        assert_eq!(res, None);
+        assert_eq!(tries.get(), 2);
+
+        // zero retries don't even try
+        let res = reopen(0, || Ok::<_, String>(42)).await;
+        assert_eq!(res, None);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite() {
+        let path = test_db_path("sqlite");
+        let store = SqliteStore::open(path.clone(), 1).unwrap();
+        let addr = IpAddr::from([1, 2, 3, 4]);
+        store.record_visit(addr, "FR").await.unwrap();
+        store.record_visit(addr, "FR").await.unwrap();
+        store.record_visit(addr, "US").await.unwrap();
+        let mut analytics = store.get_analytics().await.unwrap();
+        analytics.sort();
+        assert_eq!(analytics, [("FR".to_owned(), 2), ("US".to_owned(), 1)]);
+
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        let mut recent = store.get_analytics_since(hour_ago).await.unwrap();
+        recent.sort();
+        assert_eq!(recent, analytics);
+        let later = SystemTime::now() + Duration::from_secs(2);
+        assert!(store.get_analytics_since(later).await.unwrap().is_empty());
+
+        store.clear().await.unwrap();
+        assert!(store.get_analytics().await.unwrap().is_empty());
+        assert!(store
+            .get_analytics_since(UNIX_EPOCH)
+            .await
+            .unwrap()
+            .is_empty());
+        store.record_visit(addr, "FR").await.unwrap();
+        store.record_visit(addr, "FR").await.unwrap();
+        store.record_visit(addr, "US").await.unwrap();
+
+        // a broken connection is swapped for a fresh one, and the visit
+        // still counted
+        let broken = test_db_path("broken");
+        std::fs::write(&broken, "not a database, just some text ".repeat(10)).unwrap();
+        *store.conn.lock().await = Connection::open(&broken).unwrap();
+        store.record_visit(addr, "US").await.unwrap();
+        let mut analytics = store.get_analytics().await.unwrap();
+        analytics.sort();
+        assert_eq!(analytics, [("FR".to_owned(), 2), ("US".to_owned(), 2)]);
+
+        // without retries, the write fails where the caller can see it
+        let store = SqliteStore {
+            reconnect_retries: 0,
+            ..store
+        };
+        *store.conn.lock().await = Connection::open(&broken).unwrap();
+        let e = store.record_visit(addr, "US").await.unwrap_err();
+        assert!(is_connection_error(e.downcast_ref().unwrap()));
+        std::fs::remove_file(broken).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        // as locat leaves it, with counts but no visit times
+        let path = test_db_path("migrate");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE analytics (iso_code TEXT PRIMARY KEY, count INTEGER NOT NULL);
+            INSERT INTO analytics VALUES ('FR', 5);",
+        )
+        .unwrap();
+        drop(conn);
+
+        let store = SqliteStore::open(path.clone(), 0).unwrap();
+        store
+            .record_visit(IpAddr::from([1, 2, 3, 4]), "FR")
+            .await
+            .unwrap();
+        assert_eq!(store.get_analytics().await.unwrap(), [("FR".to_owned(), 6)]);
+        assert_eq!(
+            store.get_analytics_since(UNIX_EPOCH).await.unwrap(),
+            [("FR".to_owned(), 1)]
+        );
+
+        // and only once
+        drop(store);
+        let conn = open(&path).unwrap();
+        let version: u32 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_backend() {
+        assert_eq!("sqlite".parse(), Ok(AnalyticsBackend::Sqlite));
+        assert_eq!("memory".parse(), Ok(AnalyticsBackend::Memory));
+        assert!("redis".parse::<AnalyticsBackend>().is_err());
+    }
+}
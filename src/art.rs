@@ -0,0 +1,557 @@
+use std::num::NonZeroU32;
+
+use artem::options::{OptionBuilder, TargetType};
+
+use crate::{negotiate, svg};
+
+/// Width of the art, in characters, when `?width=` is absent or unparseable
+pub const DEFAULT_WIDTH: u32 = 80;
+/// Range `?width=` gets clamped to
+pub const WIDTH_RANGE: std::ops::RangeInclusive<u32> = 20..=400;
+
+pub fn resolve_width(width: Option<&str>) -> u32 {
+    resolve_width_or(width, DEFAULT_WIDTH)
+}
+
+/// Like [resolve_width], with another default
+pub fn resolve_width_or(width: Option<&str>, default: u32) -> u32 {
+    match width.and_then(|w| w.trim().parse::<u32>().ok()) {
+        Some(w) => w.clamp(*WIDTH_RANGE.start(), *WIDTH_RANGE.end()),
+        None => default,
+    }
+}
+
+/// How much taller than wide characters are taken to be, in hundredths,
+/// when `?ratio=` is absent or unparseable. That's what artem uses.
+pub const DEFAULT_RATIO: u32 = 42;
+/// Range `?ratio=` gets clamped to, in hundredths
+pub const RATIO_RANGE: std::ops::RangeInclusive<u32> = 30..=100;
+
+/// Parses `?ratio=`, like `0.5`, into hundredths
+pub fn resolve_ratio(ratio: Option<&str>) -> u32 {
+    match ratio
+        .and_then(|r| r.trim().parse::<f32>().ok())
+        .filter(|r| r.is_finite())
+    {
+        Some(r) => {
+            ((r * 100.0).round().max(0.0) as u32).clamp(*RATIO_RANGE.start(), *RATIO_RANGE.end())
+        }
+        None => DEFAULT_RATIO,
+    }
+}
+
+/// The art inside one of artem's HTML pages, without the page around it
+pub fn html_contents(html: &str) -> &str {
+    let body = html.split_once("<pre>").map_or(html, |(_, body)| body);
+    body.rsplit_once("</pre>").map_or(body, |(body, _)| body)
+}
+
+/// Lays out text art that didn't come out of artem, like the fallback cat,
+/// in `format`. It's never colored.
+pub fn from_plain(text: &str, format: ArtFormat) -> String {
+    match format {
+        ArtFormat::Html => {
+            let mut html = String::from(concat!(
+                r#"<!DOCTYPE html><html lang="en"><head><meta charset="UTF-8">"#,
+                "<style>* {font-family: Courier;}</style></head><body><pre>",
+            ));
+            svg::escape_into(&mut html, text);
+            html.push_str("</pre></body></html>");
+            html
+        }
+        ArtFormat::Text | ArtFormat::Ansi => text.to_owned(),
+        // bare characters are what monochrome HTML is made of
+        ArtFormat::Svg => svg::from_html(text),
+    }
+}
+
+/// What the art gets rendered as
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ArtFormat {
+    /// A full, colored HTML page, for browsers
+    Html,
+    /// Bare characters, for terminals
+    Text,
+    /// Colored characters laid out on a grid, for embedding
+    Svg,
+    /// Characters colored with ANSI escapes, for terminals that can show
+    /// them and `catscii oneshot`
+    Ansi,
+}
+
+/// Not a registered type: clients ask for it with `Accept` to get ANSI
+/// colors, which are still sent as `text/plain`
+pub const ANSI_MEDIA_TYPE: &str = "text/x-ansi";
+
+impl ArtFormat {
+    /// Picks a format from the request's `Accept` header, HTML unless
+    /// plain text (or ANSI, as [ANSI_MEDIA_TYPE]) is preferred.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        match negotiate::preferred(accept, &["text/html", "text/plain", ANSI_MEDIA_TYPE]) {
+            Some("text/plain") => Self::Text,
+            Some(ANSI_MEDIA_TYPE) => Self::Ansi,
+            _ => Self::Html,
+        }
+    }
+
+    /// Parses the `?format=` query parameter
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "html" => Some(Self::Html),
+            "text" | "txt" => Some(Self::Text),
+            "svg" => Some(Self::Svg),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Html => "text/html; charset=utf-8",
+            Self::Text | Self::Ansi => "text/plain; charset=utf-8",
+            Self::Svg => "image/svg+xml",
+        }
+    }
+
+    /// For files saved with `?download=true`
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Html => "html",
+            Self::Text | Self::Ansi => "txt",
+            Self::Svg => "svg",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Html => "html",
+            Self::Text => "text",
+            Self::Svg => "svg",
+            Self::Ansi => "ansi",
+        }
+    }
+
+    fn target(self, color: bool) -> TargetType {
+        match self {
+            Self::Html => TargetType::HtmlFile(color, color),
+            Self::Text => TargetType::File,
+            // HTML without backgrounds is the easiest to lay out as SVG, see
+            // `svg::from_html`
+            Self::Svg => TargetType::HtmlFile(color, false),
+            Self::Ansi => TargetType::Shell(color, false),
+        }
+    }
+}
+
+/// Which characters the art is drawn with, picked with `?style=`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ArtStyle {
+    /// Artem's own ramp
+    #[default]
+    Classic,
+    /// Shaded blocks, for a pixelated look
+    Blocks,
+    /// A long ramp, for smoother gradients
+    Dense,
+    /// Just a few characters, for a sketch
+    Minimal,
+    /// Whatever `?charset=` says
+    Custom(Charset),
+}
+
+impl ArtStyle {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "classic" => Some(Self::Classic),
+            "blocks" => Some(Self::Blocks),
+            "dense" => Some(Self::Dense),
+            "minimal" => Some(Self::Minimal),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Classic => "classic",
+            Self::Blocks => "blocks",
+            Self::Dense => "dense",
+            Self::Minimal => "minimal",
+            Self::Custom(_) => "custom",
+        }
+    }
+
+    /// From darkest to lightest, `None` to keep artem's. Artem doesn't
+    /// escape its HTML output, so none of these may contain `<`, `>` or `&`.
+    fn characters(self) -> Option<String> {
+        let characters = match &self {
+            Self::Classic => return None,
+            Self::Blocks => "█▓▒░ ",
+            Self::Dense => r#"$@B%8WM#*oahkbdpqwmZO0QLCJUYXzcvunxrjft/\|()1{}[]?-_+~i!lI;:,"^`'. "#,
+            Self::Minimal => "#+. ",
+            Self::Custom(charset) => charset.as_str(),
+        };
+        Some(characters.to_owned())
+    }
+}
+
+/// How many characters `?charset=` may have
+pub const MAX_CHARSET_LEN: usize = 32;
+
+/// Characters picked with `?charset=`, from darkest to lightest. Kept in an
+/// array rather than a `String` so [RenderOptions] stays `Copy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Charset {
+    /// UTF-8, room for [MAX_CHARSET_LEN] of the widest characters
+    bytes: [u8; MAX_CHARSET_LEN * 4],
+    len: u8,
+}
+
+impl Charset {
+    /// `None` if `charset` is empty, too long, or has characters that don't
+    /// belong in art: control characters, and `<`, `>` or `&` (see
+    /// [ArtStyle::characters])
+    pub fn parse(charset: &str) -> Option<Self> {
+        let valid = (1..=MAX_CHARSET_LEN).contains(&charset.chars().count())
+            && !charset
+                .chars()
+                .any(|c| c.is_control() || matches!(c, '<' | '>' | '&'));
+        if !valid {
+            return None;
+        }
+        let mut bytes = [0; MAX_CHARSET_LEN * 4];
+        bytes[..charset.len()].copy_from_slice(charset.as_bytes());
+        Some(Self {
+            bytes,
+            len: charset.len() as u8,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..self.len as usize]).expect("parsed from a str")
+    }
+}
+
+/// Presets trading detail for size, picked with `?quality=`. `?width=` and
+/// `?style=` still win over them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Quality {
+    /// Small and sketchy, for slow connections
+    Low,
+    /// What you get without any of `quality`, `width` or `style`
+    #[default]
+    Medium,
+    /// Wide, with smooth gradients
+    High,
+}
+
+impl Quality {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "low" => Some(Self::Low),
+            "medium" => Some(Self::Medium),
+            "high" => Some(Self::High),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Medium => "medium",
+            Self::High => "high",
+        }
+    }
+
+    pub fn width(self) -> u32 {
+        match self {
+            Self::Low => 40,
+            Self::Medium => DEFAULT_WIDTH,
+            Self::High => 160,
+        }
+    }
+
+    pub fn style(self) -> ArtStyle {
+        match self {
+            Self::Low => ArtStyle::Minimal,
+            Self::Medium => ArtStyle::Classic,
+            Self::High => ArtStyle::Dense,
+        }
+    }
+}
+
+/// Scales `image` down so neither side is longer than `max_dimension`,
+/// keeping its aspect ratio. Smaller images are left alone.
+pub fn fit(image: image::DynamicImage, max_dimension: u32) -> image::DynamicImage {
+    if image.width().max(image.height()) <= max_dimension {
+        return image;
+    }
+    image.resize(
+        max_dimension,
+        max_dimension,
+        image::imageops::FilterType::Triangle,
+    )
+}
+
+/// Turns `image` black and white: pixels at least as bright as `cutoff`
+/// become white, darker ones black, so the art only uses the lightest and
+/// darkest characters
+pub fn threshold(image: image::DynamicImage, cutoff: u8) -> image::DynamicImage {
+    let mut gray = image.into_luma8();
+    for pixel in gray.pixels_mut() {
+        pixel.0[0] = if pixel.0[0] >= cutoff { u8::MAX } else { 0 };
+    }
+    image::DynamicImage::ImageLuma8(gray)
+}
+
+/// Everything that changes how a given image gets converted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RenderOptions {
+    pub width: u32,
+    pub format: ArtFormat,
+    /// Monochrome otherwise. Plain text never has colors.
+    pub color: bool,
+    pub style: ArtStyle,
+    /// Light parts of the image get the dense characters, for images with
+    /// dark backgrounds
+    pub invert: bool,
+    /// Corrects for characters being taller than wide, in hundredths. In a
+    /// float, this couldn't be part of a cache key.
+    pub ratio: u32,
+    /// Cutoff for [threshold], if the image is made black and white first
+    pub threshold: Option<u8>,
+}
+
+/// What `/` draws without any query parameters
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            width: DEFAULT_WIDTH,
+            format: ArtFormat::Html,
+            color: true,
+            style: ArtStyle::default(),
+            invert: false,
+            ratio: DEFAULT_RATIO,
+            threshold: None,
+        }
+    }
+}
+
+impl RenderOptions {
+    /// Converts `image` to art, in the requested format
+    pub fn render(self, image: image::DynamicImage) -> String {
+        let image = match self.threshold {
+            Some(cutoff) => threshold(image, cutoff),
+            None => image,
+        };
+        let art = artem::convert(image, self.to_artem());
+        match self.format {
+            ArtFormat::Svg => svg::from_html(&art),
+            ArtFormat::Html | ArtFormat::Text | ArtFormat::Ansi => art,
+        }
+    }
+
+    fn to_artem(self) -> artem::options::Option {
+        let mut builder = OptionBuilder::new();
+        builder
+            .target(self.format.target(self.color))
+            .target_size(NonZeroU32::new(self.width).expect("art width is clamped above zero"))
+            .invert(self.invert)
+            .scale(self.ratio as f32 / 100.0);
+        // `characters` doesn't return a `&mut`, so it goes last
+        match self.style.characters() {
+            Some(characters) => builder.characters(characters).build(),
+            None => builder.build(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_width() {
+        assert_eq!(resolve_width(None), DEFAULT_WIDTH);
+        assert_eq!(resolve_width(Some("120")), 120);
+        assert_eq!(resolve_width(Some("5")), 20);
+        assert_eq!(resolve_width(Some("100000")), 400);
+        assert_eq!(resolve_width(Some("wide")), DEFAULT_WIDTH);
+        assert_eq!(resolve_width(Some("-3")), DEFAULT_WIDTH);
+    }
+
+    #[test]
+    fn test_ratio() {
+        assert_eq!(resolve_ratio(None), DEFAULT_RATIO);
+        assert_eq!(resolve_ratio(Some("0.5")), 50);
+        assert_eq!(resolve_ratio(Some(" 0.333 ")), 33);
+        assert_eq!(resolve_ratio(Some("0.1")), 30);
+        assert_eq!(resolve_ratio(Some("-2")), 30);
+        assert_eq!(resolve_ratio(Some("7")), 100);
+        assert_eq!(resolve_ratio(Some("square")), DEFAULT_RATIO);
+        assert_eq!(resolve_ratio(Some("NaN")), DEFAULT_RATIO);
+    }
+
+    #[test]
+    fn test_from_plain() {
+        let art = " /\\_/\\\n( o.o )\n > ^ <\n";
+        assert_eq!(from_plain(art, ArtFormat::Text), art);
+        let html = from_plain(art, ArtFormat::Html);
+        assert!(
+            html.contains("<pre> /\\_/\\\n( o.o )\n &gt; ^ &lt;\n</pre>"),
+            "{html}"
+        );
+        let svg = from_plain(art, ArtFormat::Svg);
+        assert_eq!(svg.matches("<text ").count(), 3, "{svg}");
+    }
+
+    #[test]
+    fn test_styles() {
+        let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, 128])
+        }));
+        for style in [
+            ArtStyle::Classic,
+            ArtStyle::Blocks,
+            ArtStyle::Dense,
+            ArtStyle::Minimal,
+        ] {
+            assert_eq!(ArtStyle::from_name(style.name()), Some(style));
+            let options = RenderOptions {
+                width: 20,
+                format: ArtFormat::Text,
+                color: false,
+                style,
+                invert: false,
+                ratio: DEFAULT_RATIO,
+                threshold: None,
+            };
+            let art = options.render(image.clone());
+            if let Some(characters) = style.characters() {
+                assert!(
+                    art.chars().all(|c| c == '\n' || characters.contains(c)),
+                    "{style:?}: {art}"
+                );
+            }
+        }
+        assert_eq!(ArtStyle::from_name("fancy"), None);
+    }
+
+    #[test]
+    fn test_fit() {
+        let image =
+            |width, height| image::DynamicImage::ImageRgb8(image::RgbImage::new(width, height));
+        let size = |image: image::DynamicImage| (image.width(), image.height());
+        assert_eq!(size(fit(image(4000, 1000), 1024)), (1024, 256));
+        assert_eq!(size(fit(image(500, 2048), 1024)), (250, 1024));
+        assert_eq!(size(fit(image(1024, 800), 1024)), (1024, 800));
+        assert_eq!(size(fit(image(64, 64), 1024)), (64, 64));
+    }
+
+    #[test]
+    fn test_threshold() {
+        let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(4, 1, |x, _| {
+            image::Rgb([x as u8 * 80, x as u8 * 80, x as u8 * 80])
+        }));
+        let gray = threshold(image.clone(), 100).into_luma8();
+        assert_eq!(gray.as_raw(), &[0, 0, 255, 255]);
+        // the cutoff itself is white
+        let gray = threshold(image.clone(), 80).into_luma8();
+        assert_eq!(gray.as_raw(), &[0, 255, 255, 255]);
+        let gray = threshold(image, 0).into_luma8();
+        assert_eq!(gray.as_raw(), &[255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_charset() {
+        let charset = Charset::parse("@%. ").unwrap();
+        assert_eq!(charset.as_str(), "@%. ");
+        assert_eq!(
+            ArtStyle::Custom(charset).characters().as_deref(),
+            Some("@%. ")
+        );
+        assert_eq!(Charset::parse("█▓▒░ ").unwrap().as_str(), "█▓▒░ ");
+
+        let longest = "x".repeat(MAX_CHARSET_LEN);
+        assert_eq!(Charset::parse(&longest).unwrap().as_str(), longest);
+        assert_eq!(Charset::parse(&format!("{longest}x")), None);
+        assert_eq!(Charset::parse(""), None);
+        assert_eq!(Charset::parse("#<b>"), None);
+        assert_eq!(Charset::parse("a&b"), None);
+        assert_eq!(Charset::parse("ab\n"), None);
+    }
+
+    #[test]
+    fn test_quality() {
+        assert_eq!(Quality::default(), Quality::Medium);
+        assert_eq!(Quality::Medium.width(), DEFAULT_WIDTH);
+        assert_eq!(Quality::Medium.style(), ArtStyle::default());
+        assert_eq!(
+            (Quality::Low.width(), Quality::Low.style()),
+            (40, ArtStyle::Minimal)
+        );
+        assert_eq!(
+            (Quality::High.width(), Quality::High.style()),
+            (160, ArtStyle::Dense)
+        );
+        for quality in [Quality::Low, Quality::Medium, Quality::High] {
+            assert_eq!(Quality::from_name(quality.name()), Some(quality));
+            assert!(WIDTH_RANGE.contains(&quality.width()));
+        }
+        assert_eq!(Quality::from_name("ultra"), None);
+
+        assert_eq!(resolve_width_or(None, 40), 40);
+        assert_eq!(resolve_width_or(Some("wide"), 160), 160);
+        assert_eq!(resolve_width_or(Some("100"), 40), 100);
+    }
+
+    #[test]
+    fn test_to_artem() {
+        let options = RenderOptions {
+            width: 40,
+            format: ArtFormat::Html,
+            color: false,
+            style: ArtStyle::Minimal,
+            invert: false,
+            ratio: 50,
+            threshold: None,
+        };
+        assert!(!options.to_artem().invert);
+
+        let inverted = RenderOptions {
+            invert: true,
+            ..options
+        }
+        .to_artem();
+        assert!(inverted.invert);
+        assert_eq!(inverted.target_size, 40);
+        assert_eq!(inverted.scale, 0.5);
+        assert_eq!(inverted.target, TargetType::HtmlFile(false, false));
+        assert_eq!(inverted.characters, ArtStyle::Minimal.characters().unwrap());
+
+        let custom = RenderOptions {
+            style: ArtStyle::Custom(Charset::parse("Xx. ").unwrap()),
+            ..options
+        }
+        .to_artem();
+        assert_eq!(custom.characters, "Xx. ");
+        // artem's own ramp otherwise
+        let classic = RenderOptions {
+            style: ArtStyle::Classic,
+            ..options
+        }
+        .to_artem();
+        assert_eq!(
+            classic.characters,
+            artem::options::OptionBuilder::new().build().characters
+        );
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(ArtFormat::negotiate(None), ArtFormat::Html);
+        assert_eq!(ArtFormat::negotiate(Some("*/*")), ArtFormat::Html);
+        assert_eq!(ArtFormat::negotiate(Some("text/plain")), ArtFormat::Text);
+        assert_eq!(ArtFormat::negotiate(Some("image/png")), ArtFormat::Html);
+        assert_eq!(ArtFormat::negotiate(Some("text/x-ansi")), ArtFormat::Ansi);
+        assert_eq!(ArtFormat::negotiate(Some("text/*")), ArtFormat::Html);
+    }
+}
@@ -0,0 +1,63 @@
+//! Files baked into the binary, so deploying is still a matter of copying
+//! one file
+
+use axum::{
+    body::BoxBody,
+    http::header,
+    response::{IntoResponse, Response},
+};
+
+/// Browsers keep these for a week before asking again
+const CACHE_CONTROL: &str = "public, max-age=604800";
+
+pub struct Asset {
+    /// Relative to `/static/`
+    pub path: &'static str,
+    pub content_type: &'static str,
+    pub bytes: &'static [u8],
+}
+
+const ASSETS: &[Asset] = &[
+    Asset {
+        path: "favicon.ico",
+        content_type: "image/x-icon",
+        bytes: include_bytes!("static/favicon.ico"),
+    },
+    Asset {
+        path: "robots.txt",
+        content_type: "text/plain; charset=utf-8",
+        bytes: include_bytes!("static/robots.txt"),
+    },
+];
+
+pub fn find(path: &str) -> Option<&'static Asset> {
+    ASSETS.iter().find(|asset| asset.path == path)
+}
+
+impl IntoResponse for &'static Asset {
+    fn into_response(self) -> Response<BoxBody> {
+        (
+            [
+                (header::CONTENT_TYPE, self.content_type),
+                (header::CACHE_CONTROL, CACHE_CONTROL),
+            ],
+            self.bytes,
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let favicon = find("favicon.ico").unwrap();
+        // an ICO header: reserved, then type 1 (icon)
+        assert_eq!(&favicon.bytes[..4], &[0, 0, 1, 0]);
+        assert!(find("robots.txt").is_some());
+        assert!(find("../Cargo.toml").is_none());
+        assert!(find("").is_none());
+    }
+}
@@ -0,0 +1,151 @@
+//! A circuit breaker for image sources, so a struggling upstream isn't
+//! hammered with retries from every request
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub struct CircuitBreaker {
+    /// Consecutive failures that open the circuit, 0 to never open it
+    threshold: u32,
+    /// How long the circuit stays open before a request is let through to
+    /// probe upstream
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A single request is probing upstream. If it never reports back, say
+    /// its future got dropped, another one gets to probe after `cooldown`.
+    HalfOpen {
+        probe_since: Instant,
+    },
+}
+
+/// The circuit is open, upstream wasn't called
+#[derive(Debug)]
+pub struct CircuitOpen;
+
+impl std::fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("circuit breaker is open")
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Whether upstream may be called right now. Every call that's let
+    /// through must be followed by [Self::record].
+    pub fn allow(&self) -> Result<(), CircuitOpen> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let ready = match *state {
+            State::Closed { .. } => return Ok(()),
+            State::Open { until } => now >= until,
+            State::HalfOpen { probe_since } => now >= probe_since + self.cooldown,
+        };
+        if !ready {
+            return Err(CircuitOpen);
+        }
+        *state = State::HalfOpen { probe_since: now };
+        Ok(())
+    }
+
+    /// Reports how a call to upstream went
+    pub fn record(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        *state = match (*state, success) {
+            (_, true) => State::Closed { failures: 0 },
+            (State::Closed { failures }, false) if failures + 1 < self.threshold => State::Closed {
+                failures: failures + 1,
+            },
+            // a failed probe opens the circuit right back up
+            (_, false) if self.threshold > 0 => State::Open {
+                until: Instant::now() + self.cooldown,
+            },
+            (state, false) => state,
+        };
+    }
+
+    /// `closed`, `open` or `half_open`, for spans
+    pub fn state_name(&self) -> &'static str {
+        match *self.state.lock().unwrap() {
+            State::Closed { .. } => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half_open",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_threshold() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        breaker.allow().unwrap();
+        breaker.record(false);
+        assert_eq!(breaker.state_name(), "closed");
+        breaker.record(true);
+        breaker.record(false);
+        assert_eq!(breaker.state_name(), "closed");
+        breaker.record(false);
+        assert_eq!(breaker.state_name(), "open");
+        breaker.allow().unwrap_err();
+    }
+
+    #[test]
+    fn test_half_open() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        breaker.record(false);
+        assert_eq!(breaker.state_name(), "open");
+
+        // the cooldown is over, so the next call probes
+        breaker.allow().unwrap();
+        assert_eq!(breaker.state_name(), "half_open");
+        breaker.record(false);
+        assert_eq!(breaker.state_name(), "open");
+
+        breaker.allow().unwrap();
+        breaker.record(true);
+        assert_eq!(breaker.state_name(), "closed");
+    }
+
+    #[test]
+    fn test_single_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+        *breaker.state.lock().unwrap() = State::Open {
+            until: Instant::now(),
+        };
+        breaker.allow().unwrap();
+        // the probe hasn't reported back yet
+        breaker.allow().unwrap_err();
+    }
+
+    #[test]
+    fn test_disabled() {
+        let breaker = CircuitBreaker::new(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            breaker.record(false);
+        }
+        breaker.allow().unwrap();
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+
+use crate::art::RenderOptions;
+
+/// Identifies a piece of converted art: the same image rendered with
+/// different settings is a different cache entry.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ArtKey {
+    pub url: String,
+    pub options: RenderOptions,
+}
+
+/// Converted art, with the ETag it's served with
+#[derive(Clone)]
+pub struct Art {
+    pub body: String,
+    /// Quoted, ready to go in a header
+    pub etag: String,
+    /// Width and height of the image it was drawn from, in pixels
+    pub image_size: (u32, u32),
+    /// Where that image was downloaded from
+    pub image_url: String,
+}
+
+impl Art {
+    pub fn new(
+        options: RenderOptions,
+        body: String,
+        image_size: (u32, u32),
+        image_url: String,
+    ) -> Self {
+        // the options are in there so e.g. blank art in two styles doesn't
+        // share a tag. `DefaultHasher` isn't stable across builds, which
+        // only costs a full response after a deploy.
+        let mut hasher = DefaultHasher::new();
+        options.hash(&mut hasher);
+        body.hash(&mut hasher);
+        let etag = format!("\"{:016x}\"", hasher.finish());
+        Self {
+            body,
+            etag,
+            image_size,
+            image_url,
+        }
+    }
+}
+
+/// Whether an `If-None-Match` header lists `etag`. Weak comparison, as
+/// RFC 9110 says to for `If-None-Match`.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+/// Bounded, least-recently-used cache of converted ASCII art. Entries also
+/// expire, or a seeded cat would be the same art until it's evicted, which
+/// could be never.
+pub struct ArtCache {
+    /// `None` when the cache is disabled (capacity of zero). Art comes with
+    /// when it was inserted.
+    entries: Option<Mutex<LruCache<ArtKey, (Art, Instant)>>>,
+    capacity: usize,
+    ttl: Duration,
+    /// Kept up to date on insert, so [Self::stats] doesn't need the lock
+    len: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// What `/status` says about the cache. Hits and misses count lookups: a
+/// request looks up each of its candidate images until one's there.
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
+pub struct CacheStats {
+    pub size: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl ArtCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: NonZeroUsize::new(capacity).map(|cap| Mutex::new(LruCache::new(cap))),
+            capacity,
+            ttl,
+            len: AtomicUsize::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &ArtKey) -> Option<Art> {
+        self.get_at(key, Instant::now())
+    }
+
+    /// Expired art is dropped, and counts as a miss
+    fn get_at(&self, key: &ArtKey, now: Instant) -> Option<Art> {
+        let mut entries = self.entries.as_ref()?.lock().unwrap();
+        let art = match entries.get(key) {
+            Some((art, inserted)) if now.duration_since(*inserted) < self.ttl => Some(art.clone()),
+            Some(_) => {
+                entries.pop(key);
+                self.len.store(entries.len(), Ordering::Relaxed);
+                None
+            }
+            None => None,
+        };
+        let counter = if art.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        art
+    }
+
+    pub fn insert(&self, key: ArtKey, art: Art) {
+        self.insert_at(key, art, Instant::now())
+    }
+
+    fn insert_at(&self, key: ArtKey, art: Art, now: Instant) {
+        if let Some(entries) = &self.entries {
+            let mut entries = entries.lock().unwrap();
+            entries.put(key, (art, now));
+            self.len.store(entries.len(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            size: self.len.load(Ordering::Relaxed),
+            capacity: self.capacity,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::art::{ArtFormat, ArtStyle, DEFAULT_RATIO};
+
+    #[test]
+    fn test_etag() {
+        let options = RenderOptions {
+            width: 80,
+            format: ArtFormat::Text,
+            color: true,
+            style: ArtStyle::Classic,
+            invert: false,
+            ratio: DEFAULT_RATIO,
+            threshold: None,
+        };
+        let art = Art::new(options, "MWN".into(), (8, 8), "url".into());
+        assert_eq!(
+            art.etag,
+            Art::new(options, "MWN".into(), (8, 8), "url".into()).etag
+        );
+        assert_ne!(
+            art.etag,
+            Art::new(options, "MWX".into(), (8, 8), "url".into()).etag
+        );
+        let wider = RenderOptions {
+            width: 81,
+            ..options
+        };
+        assert_ne!(
+            art.etag,
+            Art::new(wider, "MWN".into(), (8, 8), "url".into()).etag
+        );
+
+        let etag = &art.etag;
+        assert!(etag_matches(etag, etag));
+        assert!(etag_matches(&format!("W/{etag}"), etag));
+        assert!(etag_matches(&format!("\"nope\", {etag}"), etag));
+        assert!(etag_matches("*", etag));
+        assert!(!etag_matches("\"nope\"", etag));
+    }
+
+    #[test]
+    fn test_stats() {
+        let options = RenderOptions {
+            width: 80,
+            format: ArtFormat::Text,
+            color: true,
+            style: ArtStyle::Classic,
+            invert: false,
+            ratio: DEFAULT_RATIO,
+            threshold: None,
+        };
+        let key = |url: &str| ArtKey {
+            url: url.into(),
+            options,
+        };
+        let cache = ArtCache::new(2, Duration::from_secs(300));
+        assert!(cache.get(&key("a")).is_none());
+        for url in ["a", "b", "c"] {
+            cache.insert(key(url), Art::new(options, url.into(), (8, 8), url.into()));
+        }
+        assert!(cache.get(&key("c")).is_some());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                size: 2,
+                capacity: 2,
+                hits: 1,
+                misses: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_ttl() {
+        let options = RenderOptions {
+            width: 80,
+            format: ArtFormat::Text,
+            color: true,
+            style: ArtStyle::Classic,
+            invert: false,
+            ratio: DEFAULT_RATIO,
+            threshold: None,
+        };
+        let key = ArtKey {
+            url: "a".into(),
+            options,
+        };
+        let cache = ArtCache::new(2, Duration::from_secs(300));
+        let start = Instant::now();
+        cache.insert_at(
+            key.clone(),
+            Art::new(options, "a".into(), (8, 8), "url".into()),
+            start,
+        );
+
+        let later = |secs| start + Duration::from_secs(secs);
+        assert!(cache.get_at(&key, later(299)).is_some());
+        assert!(cache.get_at(&key, later(300)).is_none());
+        // gone for good, not just hidden
+        assert!(cache.get_at(&key, start).is_none());
+        assert_eq!(cache.stats().size, 0);
+        assert_eq!(cache.stats().misses, 2);
+
+        // reinserting makes it fresh again
+        cache.insert_at(
+            key.clone(),
+            Art::new(options, "a".into(), (8, 8), "url".into()),
+            later(300),
+        );
+        assert!(cache.get_at(&key, later(500)).is_some());
+    }
+}
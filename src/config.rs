@@ -0,0 +1,478 @@
+//! Every setting, read from the environment once at startup. A bad value
+//! stops the process right away, with a message saying which variable is
+//! wrong, rather than whenever it first gets used.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use color_eyre::eyre::{eyre, WrapErr};
+use tower_http::cors::AllowOrigin;
+use tracing_subscriber::filter::Targets;
+
+use crate::{
+    analytics::AnalyticsBackend,
+    download::DownloadLimits,
+    fact,
+    limits::RequestLimits,
+    source::{ApiConfig, SourceConfig},
+};
+
+pub struct Config {
+    pub listen_addr: SocketAddr,
+    /// How long in-flight requests get to finish once we're asked to stop
+    pub shutdown_timeout: Duration,
+    pub log_format: LogFormat,
+    pub log_filter: Targets,
+    /// Paths without access log lines, exact matches only
+    pub access_log_excluded: Vec<String>,
+    /// Traces aren't exported without one
+    pub honeycomb_api_key: Option<String>,
+    /// Needed for geolocation and analytics
+    pub geolite2_country_db: Option<String>,
+    pub analytics_backend: AnalyticsBackend,
+    /// Needed too with the SQLite backend
+    pub analytics_db: Option<String>,
+    /// Optional on top of those, for cities and subdivisions
+    pub geolite2_city_db: Option<String>,
+    pub analytics_batch_size: usize,
+    pub analytics_flush_interval: Duration,
+    /// Zero means no summaries
+    pub analytics_log_interval: Duration,
+    /// How many times the SQLite DB is reopened after a connection error,
+    /// zero to give up right away
+    pub analytics_reconnect_retries: u32,
+    pub image_source: SourceConfig,
+    pub catapi: ApiConfig,
+    pub dogapi: ApiConfig,
+    /// Where `/fancy` gets its facts
+    pub cat_fact_url: String,
+    pub http_timeout: Duration,
+    pub http_connect_timeout: Duration,
+    /// How long drawing a cat may take in all, upstream calls included
+    pub request_budget: Duration,
+    pub download_limits: DownloadLimits,
+    pub request_limits: RequestLimits,
+    pub art_cache_capacity: usize,
+    /// Country lookups kept in memory, zero for none
+    pub geo_cache_capacity: usize,
+    /// How long art stays cached, however often it's served
+    pub art_cache_ttl: Duration,
+    pub rate_limit_rpm: u32,
+    /// Whether `Fly-Client-IP` is the client's address. Only fly.io's proxy
+    /// makes that true, anywhere else clients can pick their own. Defaults
+    /// to whether `$FLY_APP_NAME` is set, which fly does.
+    pub trust_fly_client_ip: bool,
+    /// ISO country codes turned away with a 403, see [crate::is_blocked]
+    pub blocked_countries: Vec<String>,
+    pub max_concurrent_conversions: usize,
+    pub conversion_queue_timeout: Duration,
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_cooldown: Duration,
+    pub fallback_cat: bool,
+    /// Whether `?src=` may point at any picture, see [crate::custom_src]
+    pub allow_custom_src: bool,
+    /// Draw a cat in the background at startup, see [crate::warm_up]
+    pub warmup_on_start: bool,
+    pub admin_token: Option<String>,
+    /// `*`, or a comma-separated list of origins. Without it, there are no
+    /// CORS headers at all.
+    pub allowed_origins: Option<AllowOrigin>,
+    pub maintenance_retry_after: Duration,
+    pub trace_sample_rate: f64,
+    /// Every variable that was looked up, by name, for `GET /debug/config`
+    pub settings: BTreeMap<String, Setting>,
+}
+
+/// One variable as it was resolved
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct Setting {
+    /// `null` when unset without a default. Secrets are only ever `***`.
+    pub value: Option<String>,
+    /// Whether that's the default, rather than from the environment
+    pub default: bool,
+}
+
+/// API keys and tokens, which [Setting]s never show
+fn is_secret(name: &str) -> bool {
+    name.ends_with("_KEY") || name.ends_with("_TOKEN")
+}
+
+/// How the server writes log lines, from `$LOG_FORMAT`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One JSON object per line, what production wants
+    Json,
+    /// Multi-line and colorful, for local development
+    Pretty,
+    /// One short line per event
+    Compact,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "pretty" => Ok(Self::Pretty),
+            "compact" => Ok(Self::Compact),
+            _ => Err("expected json, pretty or compact".into()),
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Json => "json",
+            Self::Pretty => "pretty",
+            Self::Compact => "compact",
+        })
+    }
+}
+
+/// Looks variables up by name, keeping track of what it found
+struct Vars<F> {
+    var: F,
+    settings: RefCell<BTreeMap<String, Setting>>,
+}
+
+impl<F: Fn(&str) -> Option<String>> Vars<F> {
+    fn new(var: F) -> Self {
+        Self {
+            var,
+            settings: Default::default(),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        let value = (self.var)(name);
+        let setting = Setting {
+            value: match &value {
+                Some(_) if is_secret(name) => Some("***".into()),
+                value => value.clone(),
+            },
+            default: value.is_none(),
+        };
+        self.settings.borrow_mut().insert(name.to_owned(), setting);
+        value
+    }
+
+    /// `$name`, or `default` when it's unset
+    fn get_or(&self, name: &str, default: &str) -> String {
+        self.get(name).unwrap_or_else(|| {
+            self.record_default(name, default.to_owned());
+            default.to_owned()
+        })
+    }
+
+    fn record_default(&self, name: &str, default: String) {
+        let setting = Setting {
+            value: Some(default),
+            default: true,
+        };
+        self.settings.borrow_mut().insert(name.to_owned(), setting);
+    }
+
+    /// Parses `$name`, falling back to `default` when it's unset
+    fn parse_or<T>(&self, name: &str, default: T) -> color_eyre::Result<T>
+    where
+        T: FromStr + Display,
+        T::Err: Display,
+    {
+        match self.get(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|e| eyre!("${name} should be valid, got {value:?}: {e}")),
+            None => {
+                self.record_default(name, default.to_string());
+                Ok(default)
+            }
+        }
+    }
+
+    /// Like [Self::parse_or], for values that can't be zero
+    fn nonzero_or<T>(&self, name: &str, default: T) -> color_eyre::Result<T>
+    where
+        T: FromStr + Display + Default + PartialEq,
+        T::Err: Display,
+    {
+        let value = self.parse_or(name, default)?;
+        if value == T::default() {
+            return Err(eyre!("${name} should be more than zero"));
+        }
+        Ok(value)
+    }
+
+    fn secs_or(&self, name: &str, default: u64) -> color_eyre::Result<Duration> {
+        self.parse_or(name, default).map(Duration::from_secs)
+    }
+
+    fn millis_or(&self, name: &str, default: u64) -> color_eyre::Result<Duration> {
+        self.parse_or(name, default).map(Duration::from_millis)
+    }
+
+    /// `${prefix}_MAX_RETRIES` and `${prefix}_KEY`
+    fn api(&self, prefix: &str) -> color_eyre::Result<ApiConfig> {
+        let default = ApiConfig::default();
+        Ok(ApiConfig {
+            max_retries: self.parse_or(&format!("{prefix}_MAX_RETRIES"), default.max_retries)?,
+            api_key: self.get(&format!("{prefix}_KEY")),
+        })
+    }
+}
+
+impl Config {
+    pub fn from_env() -> color_eyre::Result<Self> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Reads settings with `var`, which is [std::env::var] outside of tests
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> color_eyre::Result<Self> {
+        let vars = Vars::new(var);
+        let download_defaults = DownloadLimits::default();
+        let request_defaults = RequestLimits::default();
+        let config = Self {
+            listen_addr: SocketAddr::new(
+                vars.parse_or("LISTEN_ADDR", IpAddr::from([0, 0, 0, 0]))?,
+                vars.parse_or("PORT", 8080)?,
+            ),
+            shutdown_timeout: vars.secs_or("SHUTDOWN_TIMEOUT_SECS", 30)?,
+            log_format: vars.parse_or("LOG_FORMAT", LogFormat::Json)?,
+            log_filter: {
+                let filter = vars.get_or("RUST_LOG", "info");
+                filter
+                    .parse::<Targets>()
+                    .map_err(|e| eyre!("$RUST_LOG should be valid, got {filter:?}: {e}"))?
+            },
+            access_log_excluded: vars
+                .get_or("ACCESS_LOG_EXCLUDE", "/healthz,/metrics")
+                .split(',')
+                .map(str::trim)
+                .filter(|path| !path.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            honeycomb_api_key: vars.get("HONEYCOMB_API_KEY"),
+            geolite2_country_db: vars.get("GEOLITE2_COUNTRY_DB"),
+            analytics_backend: vars.parse_or("ANALYTICS_BACKEND", AnalyticsBackend::Sqlite)?,
+            analytics_db: vars.get("ANALYTICS_DB"),
+            geolite2_city_db: vars.get("GEOLITE2_CITY_DB"),
+            analytics_batch_size: vars.nonzero_or("ANALYTICS_BATCH_SIZE", 64)?,
+            // a zero period would make the flush timer panic
+            analytics_flush_interval: vars
+                .nonzero_or("ANALYTICS_FLUSH_INTERVAL_MS", 1000)
+                .map(Duration::from_millis)?,
+            analytics_log_interval: vars.secs_or("ANALYTICS_LOG_INTERVAL_SECS", 0)?,
+            analytics_reconnect_retries: vars.parse_or("ANALYTICS_RECONNECT_RETRIES", 3)?,
+            image_source: match vars.get_or("IMAGE_SOURCE", "catapi").as_str() {
+                "catapi" | "thecatapi" => SourceConfig::TheCatApi,
+                "local" => {
+                    let dir = vars.get("LOCAL_IMAGE_DIR").ok_or_else(|| {
+                        eyre!("$LOCAL_IMAGE_DIR must be set for the local image source")
+                    })?;
+                    let dir: PathBuf = std::fs::canonicalize(&dir).wrap_err_with(|| {
+                        format!("$LOCAL_IMAGE_DIR should be a directory, got {dir:?}")
+                    })?;
+                    SourceConfig::LocalDir(dir)
+                }
+                name => return Err(eyre!("$IMAGE_SOURCE has unknown value {name:?}")),
+            },
+            catapi: vars.api("CATAPI")?,
+            dogapi: vars.api("DOGAPI")?,
+            cat_fact_url: vars.get_or("CAT_FACT_URL", fact::DEFAULT_URL),
+            http_timeout: vars.secs_or("HTTP_TIMEOUT_SECS", 10)?,
+            http_connect_timeout: vars.secs_or("HTTP_CONNECT_TIMEOUT_SECS", 5)?,
+            request_budget: vars.secs_or("REQUEST_BUDGET_SECS", 15)?,
+            download_limits: DownloadLimits {
+                max_bytes: vars.parse_or("MAX_IMAGE_BYTES", download_defaults.max_bytes)?,
+                allowed_types: match vars.get("ALLOWED_IMAGE_TYPES") {
+                    Some(types) => types
+                        .split(',')
+                        .map(|t| t.trim().to_ascii_lowercase())
+                        .filter(|t| !t.is_empty())
+                        .collect(),
+                    None => download_defaults.allowed_types,
+                },
+                // there's no scaling an image down to nothing
+                max_dimension: vars
+                    .nonzero_or("MAX_IMAGE_DIMENSION", download_defaults.max_dimension)?,
+            },
+            request_limits: RequestLimits {
+                max_body_bytes: vars
+                    .parse_or("MAX_REQUEST_BODY_BYTES", request_defaults.max_body_bytes)?,
+                max_header_bytes: vars.parse_or(
+                    "MAX_REQUEST_HEADER_BYTES",
+                    request_defaults.max_header_bytes,
+                )?,
+            },
+            art_cache_capacity: vars.parse_or("ART_CACHE_CAPACITY", 128)?,
+            art_cache_ttl: vars.secs_or("ART_CACHE_TTL_SECS", 300)?,
+            geo_cache_capacity: vars.parse_or("GEO_CACHE_CAPACITY", 1024)?,
+            rate_limit_rpm: vars.parse_or("RATE_LIMIT_RPM", 60)?,
+            trust_fly_client_ip: vars
+                .parse_or("TRUST_FLY_CLIENT_IP", vars.get("FLY_APP_NAME").is_some())?,
+            blocked_countries: vars
+                .get_or("BLOCKED_COUNTRIES", "")
+                .split(',')
+                .map(str::trim)
+                .filter(|country| !country.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            max_concurrent_conversions: vars.parse_or("MAX_CONCURRENT_CONVERSIONS", 8)?,
+            conversion_queue_timeout: vars.millis_or("CONVERSION_QUEUE_TIMEOUT_MS", 1000)?,
+            circuit_breaker_threshold: vars.parse_or("CIRCUIT_BREAKER_THRESHOLD", 5)?,
+            circuit_breaker_cooldown: vars.secs_or("CIRCUIT_BREAKER_COOLDOWN_SECS", 30)?,
+            fallback_cat: vars.parse_or("ENABLE_FALLBACK_CAT", true)?,
+            allow_custom_src: vars.parse_or("ALLOW_CUSTOM_SRC", false)?,
+            warmup_on_start: vars.parse_or("WARMUP_ON_START", false)?,
+            admin_token: vars.get("ADMIN_TOKEN"),
+            allowed_origins: match vars.get("ALLOWED_ORIGINS") {
+                None => None,
+                Some(origins) if origins.trim() == "*" => Some(AllowOrigin::any()),
+                Some(origins) => Some(AllowOrigin::list(
+                    origins
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|origin| !origin.is_empty())
+                        .map(|origin| {
+                            origin.parse().map_err(|e| {
+                                eyre!("$ALLOWED_ORIGINS should be valid, got {origin:?}: {e}")
+                            })
+                        })
+                        .collect::<color_eyre::Result<Vec<_>>>()?,
+                )),
+            },
+            maintenance_retry_after: vars.secs_or("MAINTENANCE_RETRY_AFTER_SECS", 300)?,
+            trace_sample_rate: vars.parse_or("TRACE_SAMPLE_RATE", 1.0)?,
+            settings: BTreeMap::new(),
+        };
+        Ok(Self {
+            settings: vars.settings.into_inner(),
+            ..config
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn from_vars(vars: &[(&str, &str)]) -> color_eyre::Result<Config> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Config::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = from_vars(&[]).unwrap();
+        assert_eq!(config.listen_addr, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.analytics_backend, AnalyticsBackend::Sqlite);
+        assert_eq!(config.analytics_reconnect_retries, 3);
+        assert!(matches!(config.image_source, SourceConfig::TheCatApi));
+        assert_eq!(config.catapi.max_retries, 3);
+        assert!(config.allowed_origins.is_none());
+        assert_eq!(config.access_log_excluded, ["/healthz", "/metrics"]);
+        assert!(!config.trust_fly_client_ip);
+
+        let config = from_vars(&[("PORT", "3000"), ("CATAPI_KEY", "meow")]).unwrap();
+        assert_eq!(config.listen_addr.port(), 3000);
+        assert_eq!(config.catapi.api_key.as_deref(), Some("meow"));
+        assert_eq!(config.dogapi.api_key, None);
+
+        let config = from_vars(&[("FLY_APP_NAME", "catscii")]).unwrap();
+        assert!(config.trust_fly_client_ip);
+        let config = from_vars(&[
+            ("FLY_APP_NAME", "catscii"),
+            ("TRUST_FLY_CLIENT_IP", "false"),
+        ]);
+        assert!(!config.unwrap().trust_fly_client_ip);
+
+        // empty logs everything
+        let config = from_vars(&[("ACCESS_LOG_EXCLUDE", "")]).unwrap();
+        assert!(config.access_log_excluded.is_empty());
+    }
+
+    #[test]
+    fn test_settings() {
+        let config = from_vars(&[
+            ("PORT", "3000"),
+            ("ADMIN_TOKEN", "hunter2"),
+            ("CATAPI_KEY", "meow"),
+        ])
+        .unwrap();
+        let setting = |name: &str| config.settings[name].clone();
+        let set = |value: &str, default| Setting {
+            value: Some(value.into()),
+            default,
+        };
+
+        assert_eq!(setting("PORT"), set("3000", false));
+        assert_eq!(setting("SHUTDOWN_TIMEOUT_SECS"), set("30", true));
+        assert_eq!(setting("LOG_FORMAT"), set("json", true));
+        assert_eq!(
+            setting("ACCESS_LOG_EXCLUDE"),
+            set("/healthz,/metrics", true)
+        );
+        assert_eq!(setting("ADMIN_TOKEN"), set("***", false));
+        assert_eq!(setting("CATAPI_KEY"), set("***", false));
+        assert_eq!(
+            setting("HONEYCOMB_API_KEY"),
+            Setting {
+                value: None,
+                default: true
+            }
+        );
+        // only looked up for the local source
+        assert!(!config.settings.contains_key("LOCAL_IMAGE_DIR"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let e = from_vars(&[("PORT", "eighty")]).err().unwrap();
+        assert!(e.to_string().starts_with("$PORT should be valid"), "{e}");
+        let e = from_vars(&[("IMAGE_SOURCE", "local")]).err().unwrap();
+        assert!(e.to_string().contains("$LOCAL_IMAGE_DIR"), "{e}");
+        let e = from_vars(&[("IMAGE_SOURCE", "flickr")]).err().unwrap();
+        assert!(e.to_string().contains("unknown value"), "{e}");
+    }
+
+    #[test]
+    fn test_zero() {
+        for name in [
+            "ANALYTICS_BATCH_SIZE",
+            "ANALYTICS_FLUSH_INTERVAL_MS",
+            "MAX_IMAGE_DIMENSION",
+        ] {
+            let e = from_vars(&[(name, "0")]).err().unwrap();
+            assert_eq!(e.to_string(), format!("${name} should be more than zero"));
+        }
+        // zero is fine there, it turns reconnects off
+        let config = from_vars(&[("ANALYTICS_RECONNECT_RETRIES", "0")]).unwrap();
+        assert_eq!(config.analytics_reconnect_retries, 0);
+        let config = from_vars(&[("ANALYTICS_RECONNECT_RETRIES", "5")]).unwrap();
+        assert_eq!(config.analytics_reconnect_retries, 5);
+        let config = from_vars(&[("ANALYTICS_FLUSH_INTERVAL_MS", "1")]).unwrap();
+        assert_eq!(config.analytics_flush_interval, Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!("pretty".parse(), Ok(LogFormat::Pretty));
+        assert_eq!("compact".parse(), Ok(LogFormat::Compact));
+        assert!("JSON".parse::<LogFormat>().is_err());
+    }
+}
@@ -0,0 +1,66 @@
+//! Caps how many images are downloaded and converted at once. Converting is
+//! CPU-heavy, and a spike shouldn't turn into every request being slow.
+
+use std::time::{Duration, Instant};
+
+use opentelemetry::{trace::get_active_span, KeyValue};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+pub struct ConversionLimiter {
+    semaphore: Semaphore,
+    /// How long a request waits for a slot before giving up
+    queue_timeout: Duration,
+}
+
+/// Every conversion slot stayed taken for the whole queue timeout
+#[derive(Debug)]
+pub struct Overloaded;
+
+impl std::fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("too many conversions in progress")
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+impl ConversionLimiter {
+    pub fn new(max_concurrent: usize, queue_timeout: Duration) -> Self {
+        Self {
+            semaphore: Semaphore::new(max_concurrent.clamp(1, Semaphore::MAX_PERMITS)),
+            queue_timeout,
+        }
+    }
+
+    /// Waits for a slot, recording how long that took on the current span.
+    /// The slot is given back when the permit is dropped.
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>, Overloaded> {
+        let start = Instant::now();
+        let permit = tokio::time::timeout(self.queue_timeout, self.semaphore.acquire()).await;
+        get_active_span(|span| {
+            span.set_attribute(KeyValue::new(
+                "conversion_wait_ms",
+                start.elapsed().as_millis() as i64,
+            ))
+        });
+        match permit {
+            Ok(Ok(permit)) => Ok(permit),
+            // the semaphore is never closed, so that's a timeout
+            Ok(Err(_)) | Err(_) => Err(Overloaded),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let limiter = ConversionLimiter::new(1, Duration::from_millis(10));
+        let permit = limiter.acquire().await.unwrap();
+        limiter.acquire().await.unwrap_err();
+        drop(permit);
+        let _permit = limiter.acquire().await.unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+//! `?src=`, to draw any picture on the web rather than a cat. That means
+//! fetching whatever URL we're handed, so it's off unless
+//! `$ALLOW_CUSTOM_SRC` is set, and only ever fetches `http(s)` URLs on
+//! public addresses: nothing on our own network, cloud metadata endpoints
+//! included.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use color_eyre::eyre::WrapErr;
+
+/// A URL we won't fetch, and why
+#[derive(Debug)]
+pub struct ForbiddenSource(pub String);
+
+impl std::fmt::Display for ForbiddenSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "forbidden image URL: {}", self.0)
+    }
+}
+
+impl std::error::Error for ForbiddenSource {}
+
+/// Checks what can be checked without the network
+pub fn parse(src: &str) -> Result<reqwest::Url, ForbiddenSource> {
+    let url = reqwest::Url::parse(src).map_err(|e| ForbiddenSource(format!("invalid URL: {e}")))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(ForbiddenSource(format!("{} URLs", url.scheme())));
+    }
+    if url.host_str().is_none() {
+        return Err(ForbiddenSource("no host".into()));
+    }
+    Ok(url)
+}
+
+/// Whether `ip` is somewhere on the internet, rather than loopback, private,
+/// link-local (where cloud metadata lives) and the like
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "this network", 0.0.0.0/8
+                || a == 0
+                // carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64)
+                // benchmarking, 198.18.0.0/15
+                || (a == 198 && b & 0xfe == 18)
+                // reserved, 240.0.0.0/4
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            // IPv4 in disguise goes wherever that IPv4 address would
+            if let Some(ip) = embedded_ipv4(ip) {
+                return is_public(ip.into());
+            }
+            let [first, second, ..] = ip.segments();
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // local-use NAT64, 64:ff9b:1::/48
+                || (first == 0x64 && second == 0xff9b)
+                // unique local, fc00::/7
+                || first & 0xfe00 == 0xfc00
+                // link-local, fe80::/10
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/// The IPv4 address inside `ip`: mapped (`::ffff:0:0/96`), compatible
+/// (`::/96`), NAT64 (`64:ff9b::/96`) or 6to4 (`2002::/16`)
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    // both of the first two
+    if let Some(ip) = ip.to_ipv4() {
+        return Some(ip);
+    }
+    let octets = ip.octets();
+    let ipv4 = |start: usize| {
+        Ipv4Addr::new(
+            octets[start],
+            octets[start + 1],
+            octets[start + 2],
+            octets[start + 3],
+        )
+    };
+    match ip.segments() {
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(ipv4(12)),
+        [0x2002, ..] => Some(ipv4(2)),
+        _ => None,
+    }
+}
+
+/// Resolves `url`'s host, failing with [ForbiddenSource] unless every one of
+/// its addresses is public, and returns a client that only connects to
+/// those. Letting reqwest resolve the name again could get a different,
+/// private answer. Redirects aren't followed either, they could go anywhere.
+pub async fn pinned_client(url: &reqwest::Url) -> color_eyre::Result<reqwest::Client> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| ForbiddenSource("no host".into()))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    // IPv6 literals come bracketed
+    let literal = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>();
+    let addrs: Vec<SocketAddr> = match literal {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .wrap_err_with(|| format!("Could not resolve {host}"))?
+            .collect(),
+    };
+    if addrs.is_empty() {
+        return Err(ForbiddenSource(format!("{host} has no addresses")).into());
+    }
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        return Err(ForbiddenSource(format!("{host} is at {}", addr.ip())).into());
+    }
+
+    let mut client = reqwest::Client::builder()
+        .user_agent(concat!("catscii/", env!("CARGO_PKG_VERSION")))
+        .redirect(reqwest::redirect::Policy::none())
+        .no_proxy();
+    if literal.is_err() {
+        client = client.resolve_to_addrs(host, &addrs);
+    }
+    Ok(client.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        parse("https://cdn2.thecatapi.com/images/abc.jpg").unwrap();
+        parse("http://example.com:8080/cat.png").unwrap();
+        parse("file:///etc/passwd").unwrap_err();
+        parse("ftp://example.com/cat.png").unwrap_err();
+        parse("not a url").unwrap_err();
+    }
+
+    #[test]
+    fn test_is_public() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+        assert!(public("1.1.1.1"));
+        assert!(public("2606:4700:4700::1111"));
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "240.0.0.1",
+            "255.255.255.255",
+            // IPv4-compatible
+            "::127.0.0.1",
+            "::a9fe:a9fe",
+            // NAT64
+            "64:ff9b::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b:1::1",
+            // 6to4
+            "2002:7f00:1::",
+            "2002:a9fe:a9fe::1",
+        ] {
+            assert!(!public(ip), "{ip}");
+        }
+        // the same, around public IPv4 addresses
+        for ip in [
+            "198.20.0.1",
+            "::ffff:1.1.1.1",
+            "64:ff9b::1.1.1.1",
+            "2002:101:101::",
+        ] {
+            assert!(public(ip), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pinned_client() {
+        for url in [
+            "http://127.0.0.1/cat.png",
+            "http://[::1]/",
+            "http://localhost/",
+        ] {
+            let e = pinned_client(&parse(url).unwrap()).await.unwrap_err();
+            assert!(e.downcast_ref::<ForbiddenSource>().is_some(), "{url}: {e}");
+        }
+    }
+}
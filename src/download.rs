@@ -0,0 +1,187 @@
+use color_eyre::eyre::WrapErr;
+use opentelemetry::{trace::get_active_span, KeyValue};
+
+/// What we're willing to download and try to decode
+#[derive(Clone)]
+pub struct DownloadLimits {
+    /// Images bigger than this are not downloaded, let alone decoded
+    pub max_bytes: usize,
+    /// MIME types the `Content-Type` of an image has to be one of
+    pub allowed_types: Vec<String>,
+    /// Decoded images wider or taller than this are scaled down before
+    /// they're drawn: the art can't tell, but converting them is slow
+    pub max_dimension: u32,
+}
+
+/// What `image` can decode. For GIFs that's the first frame, which is all
+/// the art needs.
+const DEFAULT_ALLOWED_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+impl Default for DownloadLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 10 * 1024 * 1024,
+            allowed_types: DEFAULT_ALLOWED_TYPES.iter().map(|&t| t.into()).collect(),
+            max_dimension: 1024,
+        }
+    }
+}
+
+impl DownloadLimits {
+    /// Whether a `Content-Type` header value is on the allowlist, ignoring
+    /// parameters like `charset`
+    fn allows(&self, content_type: &str) -> bool {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        self.allowed_types
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(mime))
+    }
+}
+
+/// The image is bigger than we're willing to hold in memory
+#[derive(Debug)]
+pub struct ImageTooLarge {
+    pub max_bytes: usize,
+}
+
+impl std::fmt::Display for ImageTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "image is larger than {} bytes", self.max_bytes)
+    }
+}
+
+impl std::error::Error for ImageTooLarge {}
+
+/// The image host sent something that isn't on the allowlist
+#[derive(Debug)]
+pub struct UnsupportedImageType {
+    /// `None` if there was no `Content-Type` at all
+    pub content_type: Option<String>,
+}
+
+impl std::fmt::Display for UnsupportedImageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.content_type {
+            Some(content_type) => write!(f, "unsupported image type {content_type:?}"),
+            None => f.write_str("image has no content type"),
+        }
+    }
+}
+
+impl std::error::Error for UnsupportedImageType {}
+
+/// The host part of `url`, for telling image hosts apart in traces and
+/// errors
+fn host_of(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// Downloads `url`, or reads it from disk for `file://` URLs if
+/// `allow_files` is set. Gives up with [ImageTooLarge] past the size limit,
+/// and with [UnsupportedImageType] before reading a body whose type isn't
+/// allowed. Local files have no content type, the sources that serve them
+/// pick images by extension.
+///
+/// The host that served the image goes on the current span as `image_host`:
+/// the one redirects led to, if any.
+pub async fn download_file(
+    client: &reqwest::Client,
+    url: &str,
+    allow_files: bool,
+    limits: &DownloadLimits,
+) -> color_eyre::Result<Vec<u8>> {
+    let too_large = ImageTooLarge {
+        max_bytes: limits.max_bytes,
+    };
+
+    if url.starts_with("file://") {
+        if !allow_files {
+            color_eyre::eyre::bail!("Refusing to read local file {url}");
+        }
+        let path = reqwest::Url::parse(url)?
+            .to_file_path()
+            .map_err(|_| color_eyre::eyre::eyre!("Invalid file URL {url}"))?;
+        let len = tokio::fs::metadata(&path)
+            .await
+            .wrap_err_with(|| format!("Could not read {}", path.display()))?
+            .len();
+        if len > limits.max_bytes as u64 {
+            return Err(too_large).wrap_err_with(|| format!("Not reading {}", path.display()));
+        }
+        return tokio::fs::read(&path)
+            .await
+            .wrap_err_with(|| format!("Could not read {}", path.display()));
+    }
+
+    let host = host_of(url);
+    get_active_span(|span| span.set_attribute(KeyValue::new("image_host", host.clone())));
+    async {
+        let res = client.get(url).send().await?;
+        if let Some(served_by) = res.url().host_str().filter(|&served_by| served_by != host) {
+            get_active_span(|span| {
+                span.set_attribute(KeyValue::new("image_host", served_by.to_owned()))
+            });
+        }
+        let mut res = res.error_for_status()?;
+
+        let content_type = res
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok());
+        if !content_type.map_or(false, |t| limits.allows(t)) {
+            return Err(UnsupportedImageType {
+                content_type: content_type.map(str::to_owned),
+            }
+            .into());
+        }
+
+        // the header is only a hint: a server could lie about it, so the
+        // limit is enforced again while reading the body
+        if res
+            .content_length()
+            .map_or(false, |len| len > limits.max_bytes as u64)
+        {
+            return Err(too_large.into());
+        }
+        let mut bytes = Vec::new();
+        while let Some(chunk) = res.chunk().await? {
+            if bytes.len() + chunk.len() > limits.max_bytes {
+                return Err(too_large.into());
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok::<_, color_eyre::eyre::Report>(bytes)
+    }
+    .await
+    .wrap_err_with(|| format!("Could not download {url} from {host}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_of() {
+        assert_eq!(
+            host_of("https://cdn2.thecatapi.com/images/abc.jpg"),
+            "cdn2.thecatapi.com"
+        );
+        assert_eq!(host_of("http://127.0.0.1:8080/cat.png"), "127.0.0.1");
+        assert_eq!(host_of("not a url"), "unknown");
+        assert_eq!(host_of("data:image/png;base64,AAAA"), "unknown");
+    }
+
+    #[test]
+    fn test_allows() {
+        let limits = DownloadLimits::default();
+        assert!(limits.allows("image/png"));
+        assert!(limits.allows("Image/JPEG"));
+        assert!(limits.allows("image/gif; charset=binary"));
+        assert!(!limits.allows("text/html; charset=utf-8"));
+        assert!(!limits.allows("image/svg+xml"));
+        assert!(!limits.allows(""));
+    }
+}
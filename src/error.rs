@@ -0,0 +1,357 @@
+use axum::{
+    body::BoxBody,
+    http::{header, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use color_eyre::eyre::Report;
+use reqwest::StatusCode;
+
+use crate::{
+    breaker::CircuitOpen,
+    conversions::Overloaded,
+    custom_src::ForbiddenSource,
+    download::{ImageTooLarge, UnsupportedImageType},
+    negotiate, request_id,
+    source::NoImageFound,
+};
+
+/// Machine-readable reason a request failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// An upstream request (image source or image host) timed out
+    UpstreamTimeout,
+    /// The whole request took longer than `$REQUEST_BUDGET_SECS`
+    RequestTimeout,
+    /// An upstream server could not be reached or returned an error status
+    UpstreamUnavailable,
+    /// An upstream server answered with something we couldn't parse
+    UpstreamBadResponse,
+    /// The image source failed too often lately, so it wasn't even asked
+    UpstreamCircuitOpen,
+    /// We got an image, but couldn't load it: corrupt or unsupported format,
+    /// or not on the content type allowlist
+    ImageLoadFailed,
+    /// The image is over `$MAX_IMAGE_BYTES`
+    ImageTooLarge,
+    /// The request body is over `$MAX_REQUEST_BODY_BYTES`
+    BodyTooLarge,
+    /// The request headers are over `$MAX_REQUEST_HEADER_BYTES`
+    HeadersTooLarge,
+    /// The image source has nothing matching the request (unknown breed...)
+    NoImage,
+    /// There's no route at that path
+    NotFound,
+    /// There's a route at that path, but not for that method
+    MethodNotAllowed,
+    /// That's not an IP address
+    InvalidAddress,
+    /// `?since=` is neither a duration nor an RFC 3339 timestamp
+    InvalidSince,
+    /// More addresses than `POST /geoip/batch` looks up at once
+    BatchTooLarge,
+    /// `?style=` isn't one we know
+    UnknownStyle,
+    /// `?animal=` isn't one we have pictures of
+    UnknownAnimal,
+    /// `?charset=` is empty, too long, or has characters art can't use
+    InvalidCharset,
+    /// `?quality=` isn't a preset we know
+    UnknownQuality,
+    /// `?as=` isn't a format we can encode pictures in
+    UnsupportedEncoding,
+    /// `?src=` isn't a public `http(s)` URL
+    ForbiddenSource,
+    /// `?src=` was given, but `$ALLOW_CUSTOM_SRC` isn't set
+    CustomSrcDisabled,
+    /// The GeoLite2 database doesn't know that address
+    UnknownAddress,
+    /// Admin routes need `$ADMIN_TOKEN`
+    Unauthorized,
+    /// The client's country is in `$BLOCKED_COUNTRIES`
+    CountryBlocked,
+    /// The client is sending too many requests
+    RateLimited,
+    /// Geolocation is disabled or the analytics DB can't be queried
+    AnalyticsUnavailable,
+    /// Geolocation is disabled
+    GeolocationUnavailable,
+    /// Maintenance mode is on, see `POST /admin/maintenance`
+    Maintenance,
+    /// All conversion slots are busy, see `$MAX_CONCURRENT_CONVERSIONS`
+    Overloaded,
+    /// Anything else
+    Internal,
+}
+
+impl ErrorCode {
+    /// Figures out what went wrong by looking through the error chain
+    pub fn classify(e: &Report) -> Self {
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                return if e.is_timeout() {
+                    Self::UpstreamTimeout
+                } else if e.is_decode() {
+                    Self::UpstreamBadResponse
+                } else {
+                    Self::UpstreamUnavailable
+                };
+            }
+            if cause.downcast_ref::<image::ImageError>().is_some()
+                || cause.downcast_ref::<UnsupportedImageType>().is_some()
+            {
+                return Self::ImageLoadFailed;
+            }
+            if cause.downcast_ref::<ImageTooLarge>().is_some() {
+                return Self::ImageTooLarge;
+            }
+            if cause.downcast_ref::<CircuitOpen>().is_some() {
+                return Self::UpstreamCircuitOpen;
+            }
+            if cause.downcast_ref::<Overloaded>().is_some() {
+                return Self::Overloaded;
+            }
+            if cause.downcast_ref::<NoImageFound>().is_some() {
+                return Self::NoImage;
+            }
+            if cause.downcast_ref::<ForbiddenSource>().is_some() {
+                return Self::ForbiddenSource;
+            }
+        }
+        Self::Internal
+    }
+
+    /// Whether an upstream server is to blame, rather than the request or
+    /// the image itself
+    pub fn is_upstream(self) -> bool {
+        matches!(
+            self,
+            Self::UpstreamTimeout
+                | Self::UpstreamUnavailable
+                | Self::UpstreamBadResponse
+                | Self::UpstreamCircuitOpen
+        )
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::UpstreamTimeout => "upstream_timeout",
+            Self::RequestTimeout => "request_timeout",
+            Self::UpstreamUnavailable => "upstream_unavailable",
+            Self::UpstreamBadResponse => "upstream_bad_response",
+            Self::UpstreamCircuitOpen => "upstream_circuit_open",
+            Self::ImageLoadFailed => "image_load_failed",
+            Self::ImageTooLarge => "image_too_large",
+            Self::BodyTooLarge => "body_too_large",
+            Self::HeadersTooLarge => "headers_too_large",
+            Self::NoImage => "no_image",
+            Self::NotFound => "not_found",
+            Self::MethodNotAllowed => "method_not_allowed",
+            Self::InvalidAddress => "invalid_address",
+            Self::InvalidSince => "invalid_since",
+            Self::BatchTooLarge => "batch_too_large",
+            Self::UnknownStyle => "unknown_style",
+            Self::UnknownAnimal => "unknown_animal",
+            Self::InvalidCharset => "invalid_charset",
+            Self::UnknownQuality => "unknown_quality",
+            Self::UnsupportedEncoding => "unsupported_encoding",
+            Self::ForbiddenSource => "forbidden_source",
+            Self::CustomSrcDisabled => "custom_src_disabled",
+            Self::UnknownAddress => "unknown_address",
+            Self::Unauthorized => "unauthorized",
+            Self::CountryBlocked => "country_blocked",
+            Self::RateLimited => "rate_limited",
+            Self::AnalyticsUnavailable => "analytics_unavailable",
+            Self::GeolocationUnavailable => "geolocation_unavailable",
+            Self::Maintenance => "maintenance",
+            Self::Overloaded => "overloaded",
+            Self::Internal => "internal",
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::UpstreamTimeout | Self::RequestTimeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::UpstreamUnavailable | Self::UpstreamBadResponse | Self::ImageLoadFailed => {
+                StatusCode::BAD_GATEWAY
+            }
+            Self::ImageTooLarge | Self::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Self::NoImage | Self::NotFound | Self::UnknownAddress => StatusCode::NOT_FOUND,
+            Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Self::InvalidAddress
+            | Self::InvalidSince
+            | Self::BatchTooLarge
+            | Self::UnknownStyle
+            | Self::UnknownAnimal
+            | Self::UnknownQuality
+            | Self::InvalidCharset
+            | Self::UnsupportedEncoding
+            | Self::ForbiddenSource => StatusCode::BAD_REQUEST,
+            Self::CustomSrcDisabled => StatusCode::FORBIDDEN,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::CountryBlocked => StatusCode::FORBIDDEN,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::UpstreamCircuitOpen
+            | Self::AnalyticsUnavailable
+            | Self::GeolocationUnavailable
+            | Self::Maintenance
+            | Self::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            Self::UpstreamTimeout => "Timed out fetching a cat",
+            Self::RequestTimeout => "That cat took too long to draw",
+            Self::UpstreamUnavailable => "Could not reach the cat picture service",
+            Self::UpstreamBadResponse => "The cat picture service sent an unexpected response",
+            Self::UpstreamCircuitOpen => "The cat picture service is struggling, try again later",
+            Self::ImageLoadFailed => "The image host served an invalid image",
+            Self::ImageTooLarge => "That cat is too big to draw",
+            Self::BodyTooLarge => "The request body is too large",
+            Self::HeadersTooLarge => "The request headers are too large",
+            Self::NoImage => "No cat matches your request",
+            Self::NotFound => "Nothing here, try / instead",
+            Self::MethodNotAllowed => "Wrong method for that path, see the Allow header",
+            Self::InvalidAddress => "That's not an IP address",
+            Self::InvalidSince => {
+                "Invalid since, try a duration like 24h or a timestamp like 2024-01-31T12:00:00Z"
+            }
+            Self::BatchTooLarge => "Too many addresses, try at most 1000 at once",
+            Self::UnknownStyle => "Unknown style, try classic, blocks, dense or minimal",
+            Self::UnknownAnimal => "Unknown animal, try cat or dog",
+            Self::UnknownQuality => "Unknown quality, try low, medium or high",
+            Self::InvalidCharset => {
+                "Invalid charset, try 1 to 32 characters, without control characters, <, > or &"
+            }
+            Self::UnsupportedEncoding => "Unsupported image format, try png, jpeg, gif or webp",
+            Self::ForbiddenSource => "Only public http(s) image URLs can be drawn",
+            Self::CustomSrcDisabled => "Drawing custom images is disabled here",
+            Self::UnknownAddress => "No country is known for that address",
+            Self::Unauthorized => "Missing or wrong admin token",
+            Self::CountryBlocked => "No cats for your country, sorry",
+            Self::RateLimited => "Too many requests, slow down",
+            Self::AnalyticsUnavailable => "Analytics are unavailable right now",
+            Self::GeolocationUnavailable => "Geolocation is disabled",
+            Self::Maintenance => "The cats are down for maintenance, back soon",
+            Self::Overloaded => "Too many cats at once, try again in a moment",
+            Self::Internal => "Something went wrong",
+        }
+    }
+}
+
+/// An error as shown to clients: JSON for API consumers, text for everyone
+/// else. Either way it includes the request ID, for support.
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub json: bool,
+}
+
+#[derive(serde::Serialize)]
+struct ErrorBody {
+    error: &'static str,
+    message: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+/// Whether the client would rather read errors as JSON than text
+pub fn wants_json(accept: Option<&str>) -> bool {
+    negotiate::preferred(accept, &["text/plain", "application/json"]) == Some("application/json")
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response<BoxBody> {
+        let status = self.code.status();
+        let request_id = request_id::current();
+        if self.json {
+            let body = ErrorBody {
+                error: self.code.as_str(),
+                message: self.code.message(),
+                request_id,
+            };
+            (status, Json(body)).into_response()
+        } else {
+            let body = match request_id {
+                Some(id) => format!("{} (request ID: {id})", self.code.message()),
+                None => self.code.message().to_owned(),
+            };
+            (
+                status,
+                [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+                body,
+            )
+                .into_response()
+        }
+    }
+}
+
+/// axum answers a known path with the wrong method with an empty 405. This
+/// gives those the same body as every other error, keeping their `Allow`
+/// header.
+pub async fn method_not_allowed<B>(req: Request<B>, next: Next<B>) -> Response<BoxBody> {
+    let json = wants_json(
+        req.headers()
+            .get(header::ACCEPT)
+            .and_then(|h| h.to_str().ok()),
+    );
+    let res = next.run(req).await;
+    if res.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return res;
+    }
+    let mut error = ApiError {
+        code: ErrorCode::MethodNotAllowed,
+        json,
+    }
+    .into_response();
+    if let Some(allow) = res.headers().get(header::ALLOW) {
+        error.headers_mut().insert(header::ALLOW, allow.clone());
+    }
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let e = image::load_from_memory(b"definitely not a png").unwrap_err();
+        assert_eq!(
+            ErrorCode::classify(&Report::new(e)),
+            ErrorCode::ImageLoadFailed
+        );
+        assert_eq!(
+            ErrorCode::classify(&Report::new(NoImageFound).wrap_err("nothing")),
+            ErrorCode::NoImage
+        );
+        assert_eq!(
+            ErrorCode::classify(
+                &Report::new(ImageTooLarge { max_bytes: 1 }).wrap_err("Could not download")
+            ),
+            ErrorCode::ImageTooLarge
+        );
+        assert_eq!(
+            ErrorCode::classify(&Report::new(CircuitOpen).wrap_err("Could not get an image")),
+            ErrorCode::UpstreamCircuitOpen
+        );
+        assert_eq!(
+            ErrorCode::classify(&color_eyre::eyre::eyre!("oh no")),
+            ErrorCode::Internal
+        );
+    }
+
+    #[test]
+    fn test_wants_json() {
+        assert!(!wants_json(None));
+        assert!(!wants_json(Some("*/*")));
+        assert!(wants_json(Some("application/json")));
+        assert!(wants_json(Some(
+            "text/html, application/json;q=0.5, */*;q=0.1"
+        )));
+        assert!(!wants_json(Some("text/plain, application/json;q=0.5")));
+    }
+}
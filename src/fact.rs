@@ -0,0 +1,51 @@
+//! Cat facts, from a second API, to go with the art on `/fancy`
+
+/// Answers `{"fact": "...", "length": 42}`
+pub const DEFAULT_URL: &str = "https://catfact.ninja/fact";
+
+/// Shown when there's no fact to be had, the art is served anyway
+pub const PLACEHOLDER: &str = "Cats sleep 12 to 16 hours a day. (We couldn't get a fresh fact.)";
+
+#[derive(serde::Deserialize)]
+struct Fact {
+    fact: String,
+}
+
+pub async fn fetch(client: &reqwest::Client, url: &str) -> color_eyre::Result<String> {
+    let fact: Fact = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(fact.fact)
+}
+
+/// Art rendered as `ArtFormat::Html`, with `fact` under it
+pub fn page(art: &str, fact: &str) -> String {
+    let mut page = String::from(concat!(
+        r#"<!DOCTYPE html><html lang="en"><head><meta charset="UTF-8">"#,
+        r#"<meta name="viewport" content="width=device-width, initial-scale=1.0">"#,
+        "<title>catscii</title><style>* {font-family: Courier;}</style>",
+        "</head><body><pre>",
+    ));
+    page.push_str(crate::art::html_contents(art));
+    page.push_str("</pre><p>");
+    crate::svg::escape_into(&mut page, fact);
+    page.push_str("</p></body></html>");
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page() {
+        let art = "<html><body>\n<pre>A\n</pre></body></html>";
+        let page = page(art, "Cats <3 boxes");
+        assert!(page.contains("<pre>A\n</pre>"), "{page}");
+        assert!(page.contains("<p>Cats &lt;3 boxes</p>"), "{page}");
+    }
+}
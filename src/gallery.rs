@@ -0,0 +1,53 @@
+//! Several cats on one HTML page
+
+/// How many cats `/gallery` shows when `?count=` is absent or unparseable
+pub const DEFAULT_COUNT: usize = 4;
+/// Most cats a single gallery fetches, they all hit the image source
+pub const MAX_COUNT: usize = 10;
+
+pub fn resolve_count(count: Option<&str>) -> usize {
+    match count.and_then(|c| c.trim().parse::<usize>().ok()) {
+        Some(c) => c.clamp(1, MAX_COUNT),
+        None => DEFAULT_COUNT,
+    }
+}
+
+/// A gallery is sent in pieces, each cat as soon as it's drawn: this, then
+/// one [entry] per cat, then [FOOTER]
+pub const HEADER: &str = concat!(
+    r#"<!DOCTYPE html><html lang="en"><head><meta charset="UTF-8">"#,
+    r#"<meta name="viewport" content="width=device-width, initial-scale=1.0">"#,
+    "<title>catscii gallery</title><style>* {font-family: Courier;}</style>",
+    "</head><body>",
+);
+
+pub const FOOTER: &str = "</body></html>";
+
+/// Art rendered as `ArtFormat::Html`, ready to go between [HEADER] and
+/// [FOOTER]
+pub fn entry(art: &str) -> String {
+    format!("<pre>{}</pre>\n", crate::art::html_contents(art))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count() {
+        assert_eq!(resolve_count(None), DEFAULT_COUNT);
+        assert_eq!(resolve_count(Some("3")), 3);
+        assert_eq!(resolve_count(Some("0")), 1);
+        assert_eq!(resolve_count(Some("500")), MAX_COUNT);
+        assert_eq!(resolve_count(Some("lots")), DEFAULT_COUNT);
+    }
+
+    #[test]
+    fn test_page() {
+        let art = |ch: &str| format!("<html><body>\n<pre>{ch}\n</pre></body></html>");
+        let page = [HEADER, &entry(&art("A")), &entry(&art("B")), FOOTER].concat();
+        assert!(page.contains("<pre>A\n</pre>\n<pre>B\n</pre>"), "{page}");
+        assert_eq!(page.matches("<body>").count(), 1);
+        assert!(page.ends_with("</body></html>"));
+    }
+}
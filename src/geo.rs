@@ -0,0 +1,460 @@
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use color_eyre::eyre::WrapErr;
+use lru::LruCache;
+use opentelemetry::{
+    global,
+    trace::{FutureExt, Span, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+
+use crate::{analytics::AnalyticsStore, request_id};
+
+/// Country lookups and visit analytics. Every lookup goes through here,
+/// once per request: visits are recorded with the country found then.
+pub struct Geolocator {
+    /// Where visits are counted
+    analytics: Arc<dyn AnalyticsStore>,
+    /// Looking an address up here doesn't count as a visit. Swapped by
+    /// [Self::reload_countries], lookups already under way keep the one they
+    /// started with.
+    countries: RwLock<Arc<maxminddb::Reader<Vec<u8>>>>,
+    /// Where `countries` was read from, and is read again
+    countries_path: String,
+    /// Recent answers from `countries`, unknown addresses included. `None`
+    /// when the cache is disabled (capacity of zero).
+    cache: Option<Mutex<LruCache<IpAddr, Option<String>>>>,
+    /// A GeoLite2-City database, when there's one
+    cities: Option<maxminddb::Reader<Vec<u8>>>,
+}
+
+pub struct Lookup {
+    pub iso_code: Option<String>,
+    /// Whether the answer came from the cache rather than the database
+    pub cache_hit: bool,
+}
+
+/// Where in its country an address is, both in English
+#[derive(Debug)]
+pub struct Place<'a> {
+    pub city: Option<&'a str>,
+    /// The largest one, like a state or a region
+    pub subdivision: Option<&'a str>,
+}
+
+impl Geolocator {
+    pub fn new(
+        analytics: Arc<dyn AnalyticsStore>,
+        countries_path: String,
+        countries: maxminddb::Reader<Vec<u8>>,
+        cities: Option<maxminddb::Reader<Vec<u8>>>,
+        cache_capacity: usize,
+    ) -> Self {
+        Self {
+            analytics,
+            countries: RwLock::new(Arc::new(countries)),
+            countries_path,
+            cache: NonZeroUsize::new(cache_capacity).map(|cap| Mutex::new(LruCache::new(cap))),
+            cities,
+        }
+    }
+
+    /// Converts an address to an ISO 3166-1 alpha-2 country code. This only
+    /// reads the in-memory database, it doesn't record a visit.
+    pub fn iso_code(&self, addr: IpAddr) -> Option<String> {
+        self.lookup(addr).iso_code
+    }
+
+    /// Like [Self::iso_code], saying whether the cache had it
+    pub fn lookup(&self, addr: IpAddr) -> Lookup {
+        if let Some(cache) = &self.cache {
+            if let Some(iso_code) = cache.lock().unwrap().get(&addr) {
+                return Lookup {
+                    iso_code: iso_code.clone(),
+                    cache_hit: true,
+                };
+            }
+        }
+        let iso_code = self.lookup_uncached(addr);
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().put(addr, iso_code.clone());
+        }
+        Lookup {
+            iso_code,
+            cache_hit: false,
+        }
+    }
+
+    fn lookup_uncached(&self, addr: IpAddr) -> Option<String> {
+        let countries = self.countries.read().unwrap().clone();
+        let country: maxminddb::geoip2::Country = countries.lookup(addr).ok()?;
+        country.country?.iso_code.map(str::to_owned)
+    }
+
+    /// Reads the country database again, from the same path, for MaxMind's
+    /// weekly updates. If the new one can't be opened, the old one stays.
+    pub fn reload_countries(&self) -> color_eyre::Result<()> {
+        let path = &self.countries_path;
+        let countries = maxminddb::Reader::open_readfile(path)
+            .wrap_err_with(|| format!("Could not open {path:?}"))?;
+        *self.countries.write().unwrap() = Arc::new(countries);
+        // answers from the old one may have changed
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().clear();
+        }
+        Ok(())
+    }
+
+    /// Looks `addr` up in the city database. `None` without one, or when
+    /// the address isn't in it: countries still work either way.
+    pub fn city(&self, addr: IpAddr) -> Option<Place<'_>> {
+        let city: maxminddb::geoip2::City = self.cities.as_ref()?.lookup(addr).ok()?;
+        Some(Place {
+            city: city.city.and_then(|city| english(city.names)),
+            subdivision: city
+                .subdivisions
+                .and_then(|subdivisions| subdivisions.into_iter().next())
+                .and_then(|subdivision| english(subdivision.names)),
+        })
+    }
+
+    /// Counts a visit from `addr`, which [Self::iso_code] put in `country`.
+    /// This may write to the analytics DB: go through a [VisitRecorder]
+    /// rather than calling it on the request path.
+    pub async fn record_visit(&self, addr: IpAddr, country: &str) -> color_eyre::Result<()> {
+        self.analytics.record_visit(addr, country).await
+    }
+
+    /// Like [Self::get_analytics], for visits from `since` on
+    pub async fn get_analytics_since(
+        &self,
+        since: SystemTime,
+    ) -> color_eyre::Result<Vec<(String, u64)>> {
+        self.analytics.get_analytics_since(since).await
+    }
+
+    /// Forgets every visit, see [AnalyticsStore::clear]
+    pub async fn clear_analytics(&self) -> color_eyre::Result<()> {
+        self.analytics.clear().await
+    }
+
+    /// Returns a list of country codes with their number of requests. Doesn't
+    /// count as a visit itself.
+    pub async fn get_analytics(&self) -> color_eyre::Result<Vec<(String, u64)>> {
+        self.analytics.get_analytics().await
+    }
+}
+
+/// The English one, out of a GeoLite2 record's names
+fn english<'a>(names: Option<BTreeMap<&str, &'a str>>) -> Option<&'a str> {
+    names?.get("en").copied()
+}
+
+/// How many batches can be waiting before new visits get dropped
+const QUEUED_BATCHES: usize = 16;
+
+/// An address, its country, and the ID of the request it made
+type Visit = (IpAddr, String, Option<String>);
+
+/// Records visits off the request path: addresses and their countries are
+/// queued, then handed to the [Geolocator] in batches by a background task.
+pub struct VisitRecorder {
+    tx: mpsc::Sender<Visit>,
+    /// Taken by [Self::shutdown]
+    task: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
+}
+
+impl VisitRecorder {
+    /// Batches are flushed when they're `batch_size` long, or every
+    /// `flush_interval`, whichever comes first
+    pub fn spawn(geolocator: Arc<Geolocator>, batch_size: usize, flush_interval: Duration) -> Self {
+        let batch_size = batch_size.max(1);
+        let (tx, rx) = mpsc::channel(batch_size * QUEUED_BATCHES);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(record_visits(
+            geolocator,
+            rx,
+            shutdown_rx,
+            batch_size,
+            flush_interval,
+        ));
+        Self {
+            tx,
+            task: Mutex::new(Some((shutdown_tx, task))),
+        }
+    }
+
+    /// Queues a visit, along with the current request's ID for when it's
+    /// recorded. If the queue is full, the visit is dropped rather than
+    /// slowing the request down.
+    pub fn record(&self, addr: IpAddr, country: String) {
+        if let Err(e) = self.tx.try_send((addr, country, request_id::current())) {
+            warn!("Dropping a visit: {e}");
+        }
+    }
+
+    /// Stops taking visits, and waits for the queued ones to be recorded
+    pub async fn shutdown(&self) {
+        let Some((shutdown_tx, task)) = self.task.lock().unwrap().take() else {
+            return;
+        };
+        _ = shutdown_tx.send(());
+        if let Err(e) = task.await {
+            warn!("Visit recorder failed: {e}");
+        }
+    }
+}
+
+async fn record_visits(
+    geolocator: Arc<Geolocator>,
+    mut rx: mpsc::Receiver<Visit>,
+    mut shutdown_rx: oneshot::Receiver<()>,
+    batch_size: usize,
+    flush_interval: Duration,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut interval = tokio::time::interval(flush_interval);
+    loop {
+        tokio::select! {
+            Some(visit) = rx.recv() => {
+                batch.push(visit);
+                if batch.len() >= batch_size {
+                    flush(&geolocator, &mut batch).await;
+                }
+            }
+            _ = interval.tick() => flush(&geolocator, &mut batch).await,
+            _ = &mut shutdown_rx => break,
+        }
+    }
+
+    // whatever was queued before the shutdown still counts
+    rx.close();
+    while let Some(visit) = rx.recv().await {
+        batch.push(visit);
+    }
+    flush(&geolocator, &mut batch).await;
+}
+
+async fn flush(geolocator: &Geolocator, batch: &mut Vec<Visit>) {
+    if batch.is_empty() {
+        return;
+    }
+    let mut span = global::tracer("").start("analytics_flush");
+    span.set_attribute(KeyValue::new("visits", batch.len() as i64));
+    async {
+        for (addr, country, id) in batch.drain(..) {
+            request_id::scope(id, async {
+                if let Err(e) = geolocator.record_visit(addr, &country).await {
+                    warn!("Could not record a visit from {country}: {e}");
+                }
+            })
+            .await;
+        }
+    }
+    .with_context(Context::current_with_span(span))
+    .await;
+}
+
+/// How many countries the periodic summary names
+const SUMMARY_COUNTRIES: usize = 5;
+
+/// Logs the busiest countries every so often, for whoever only has logs to
+/// go on
+pub struct AnalyticsLogger {
+    /// Taken by [Self::shutdown]
+    task: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
+}
+
+impl AnalyticsLogger {
+    /// The first summary comes after one `interval`, not at startup
+    pub fn spawn(geolocator: Arc<Geolocator>, interval: Duration) -> Self {
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let start = tokio::time::Instant::now() + interval;
+            let mut interval = tokio::time::interval_at(start, interval);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => match geolocator.get_analytics().await {
+                        Ok(analytics) => info!("Visits so far: {}", summary(analytics)),
+                        Err(e) => warn!("Could not get analytics to log: {e}"),
+                    },
+                    _ = &mut shutdown_rx => break,
+                }
+            }
+        });
+        Self {
+            task: Mutex::new(Some((shutdown_tx, task))),
+        }
+    }
+
+    pub async fn shutdown(&self) {
+        let Some((shutdown_tx, task)) = self.task.lock().unwrap().take() else {
+            return;
+        };
+        _ = shutdown_tx.send(());
+        if let Err(e) = task.await {
+            warn!("Analytics logger failed: {e}");
+        }
+    }
+}
+
+/// Logs the same summary as [AnalyticsLogger] whenever the process gets
+/// SIGUSR1, for a look without going through HTTP. The task lives as long
+/// as the runtime, shutdown doesn't wait for it.
+#[cfg(unix)]
+pub fn log_analytics_on_sigusr1(geolocator: Arc<Geolocator>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigusr1 = match signal(SignalKind::user_defined1()) {
+        Ok(sigusr1) => sigusr1,
+        Err(e) => {
+            warn!("Could not listen for SIGUSR1: {e}");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while sigusr1.recv().await.is_some() {
+            match geolocator.get_analytics().await {
+                Ok(analytics) => info!("Visits so far: {}", summary(analytics)),
+                Err(e) => warn!("Could not get analytics to log: {e}"),
+            }
+        }
+    });
+}
+
+/// Like "FR: 12, US: 8 (2 countries, 20 visits)"
+fn summary(mut analytics: Vec<(String, u64)>) -> String {
+    analytics.sort_by(|(a_country, a_count), (b_country, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_country.cmp(b_country))
+    });
+    let visits: u64 = analytics.iter().map(|(_, count)| count).sum();
+    let top: Vec<String> = analytics
+        .iter()
+        .take(SUMMARY_COUNTRIES)
+        .map(|(country, count)| format!("{country}: {count}"))
+        .collect();
+    let top = if top.is_empty() {
+        "none".to_owned()
+    } else {
+        top.join(", ")
+    };
+    format!("{top} ({} countries, {visits} visits)", analytics.len())
+}
+
+/// A country database that puts 0.0.0.0/1 in `iso_code` and knows nothing
+/// about the rest, for tests: real ones can't be shipped with the code
+#[cfg(test)]
+pub fn test_countries(iso_code: &str) -> maxminddb::Reader<Vec<u8>> {
+    fn string(db: &mut Vec<u8>, s: &str) {
+        db.push(0x40 | s.len() as u8);
+        db.extend_from_slice(s.as_bytes());
+    }
+
+    // a single node, with 24-bit records: the left one points at the only
+    // data (node count + 16 + offset 0), the right one means not found
+    let mut db = vec![0, 0, 17, 0, 0, 1];
+    db.extend([0; 16]);
+    // {"country": {"iso_code": iso_code}}
+    db.push(0xe1);
+    string(&mut db, "country");
+    db.push(0xe1);
+    string(&mut db, "iso_code");
+    string(&mut db, iso_code);
+
+    db.extend(b"\xab\xcd\xefMaxMind.com");
+    db.push(0xe9);
+    string(&mut db, "binary_format_major_version");
+    db.extend([0xa1, 2]);
+    string(&mut db, "binary_format_minor_version");
+    db.push(0xa0);
+    string(&mut db, "build_epoch");
+    db.extend([0x00, 0x02]);
+    string(&mut db, "database_type");
+    string(&mut db, "Test-Country");
+    string(&mut db, "description");
+    db.push(0xe0);
+    string(&mut db, "ip_version");
+    db.extend([0xa1, 4]);
+    string(&mut db, "languages");
+    db.extend([0x00, 0x04]);
+    string(&mut db, "node_count");
+    db.extend([0xc1, 1]);
+    string(&mut db, "record_size");
+    db.extend([0xa1, 24]);
+    maxminddb::Reader::from_source(db).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::analytics::MemoryStore;
+
+    #[test]
+    fn test_lookup_cache() {
+        let geolocator = Geolocator::new(
+            Arc::new(MemoryStore::default()),
+            "unused".into(),
+            test_countries("FR"),
+            None,
+            2,
+        );
+        let known = IpAddr::from([1, 2, 3, 4]);
+        let unknown = IpAddr::from([200, 2, 3, 4]);
+        let lookup = geolocator.lookup(known);
+        assert_eq!(
+            (lookup.iso_code.as_deref(), lookup.cache_hit),
+            (Some("FR"), false)
+        );
+        let lookup = geolocator.lookup(known);
+        assert_eq!(
+            (lookup.iso_code.as_deref(), lookup.cache_hit),
+            (Some("FR"), true)
+        );
+        // misses are cached too
+        assert!(!geolocator.lookup(unknown).cache_hit);
+        let lookup = geolocator.lookup(unknown);
+        assert_eq!((lookup.iso_code, lookup.cache_hit), (None, true));
+
+        let disabled = Geolocator::new(
+            Arc::new(MemoryStore::default()),
+            "unused".into(),
+            test_countries("FR"),
+            None,
+            0,
+        );
+        disabled.lookup(known);
+        assert!(!disabled.lookup(known).cache_hit);
+    }
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(summary(vec![]), "none (0 countries, 0 visits)");
+        let analytics = [
+            ("US", 8),
+            ("FR", 12),
+            ("DE", 1),
+            ("JP", 1),
+            ("BR", 2),
+            ("CA", 1),
+        ]
+        .into_iter()
+        .map(|(country, count)| (country.to_owned(), count))
+        .collect();
+        assert_eq!(
+            summary(analytics),
+            "FR: 12, US: 8, BR: 2, CA: 1, DE: 1 (6 countries, 25 visits)"
+        );
+    }
+}
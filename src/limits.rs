@@ -0,0 +1,98 @@
+//! Caps on how much a client may send us. Every route is a GET for now, but
+//! that won't last.
+
+use std::sync::Arc;
+
+use axum::{
+    body::BoxBody,
+    extract::State,
+    http::{header, HeaderMap, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::error::{self, ApiError, ErrorCode};
+
+#[derive(Clone)]
+pub struct RequestLimits {
+    /// Largest `Content-Length` we accept, also enforced on bodies that
+    /// don't announce one
+    pub max_body_bytes: usize,
+    /// Largest total size of header names and values
+    pub max_header_bytes: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 16 * 1024,
+            max_header_bytes: 16 * 1024,
+        }
+    }
+}
+
+impl RequestLimits {
+    fn check(&self, headers: &HeaderMap) -> Result<(), ErrorCode> {
+        let header_bytes: usize = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        if header_bytes > self.max_header_bytes {
+            return Err(ErrorCode::HeadersTooLarge);
+        }
+
+        let content_length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.parse::<u64>().ok());
+        if content_length.map_or(false, |len| len > self.max_body_bytes as u64) {
+            return Err(ErrorCode::BodyTooLarge);
+        }
+        Ok(())
+    }
+}
+
+/// Turns away requests over the limits before any handler sees them
+pub async fn enforce<B>(
+    State(limits): State<Arc<RequestLimits>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response<BoxBody> {
+    match limits.check(req.headers()) {
+        Ok(()) => next.run(req).await,
+        Err(code) => {
+            let accept = req
+                .headers()
+                .get(header::ACCEPT)
+                .and_then(|h| h.to_str().ok());
+            ApiError {
+                code,
+                json: error::wants_json(accept),
+            }
+            .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let limits = RequestLimits {
+            max_body_bytes: 10,
+            max_header_bytes: 40,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, "10".parse().unwrap());
+        assert_eq!(limits.check(&headers), Ok(()));
+
+        headers.insert(header::CONTENT_LENGTH, "11".parse().unwrap());
+        assert_eq!(limits.check(&headers), Err(ErrorCode::BodyTooLarge));
+
+        headers.remove(header::CONTENT_LENGTH);
+        headers.insert(header::USER_AGENT, "x".repeat(40).parse().unwrap());
+        assert_eq!(limits.check(&headers), Err(ErrorCode::HeadersTooLarge));
+    }
+}
@@ -1,237 +1,3688 @@
-use std::{net::IpAddr, str::FromStr};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant, SystemTime},
+};
 
+use color_eyre::eyre::WrapErr;
 use opentelemetry::{
     global,
     trace::{get_active_span, FutureExt, Span, Status, TraceContextExt, Tracer},
     Context, KeyValue,
 };
 
-use axum::extract::State;
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Path, Query, State};
 use axum::{
     body::BoxBody,
-    http::{header, HeaderMap},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method},
+    middleware,
     response::{IntoResponse, Response},
-    routing::get,
-    Router,
+    routing::{get, post},
+    Json, Router,
 };
-use locat::Locat;
 use reqwest::StatusCode;
 use std::sync::Arc;
-use tracing::{info, warn, Level};
-use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::SubscriberInitExt};
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{AllowOrigin, CorsLayer},
+};
+use tracing::{error, info, warn, Level};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+mod access_log;
+use access_log::AccessLog;
+
+mod admin;
+use admin::AdminToken;
+
+mod assets;
+
+mod analytics;
+use analytics::{AnalyticsBackend, AnalyticsStore, MemoryStore, SqliteStore};
+
+mod art;
+use art::{ArtFormat, ArtStyle, Charset, Quality, RenderOptions};
+
+mod breaker;
+use breaker::CircuitBreaker;
+
+mod cache;
+use cache::{Art, ArtCache, ArtKey, CacheStats};
+
+mod error;
+use error::{ApiError, ErrorCode};
+
+mod config;
+use config::{Config, LogFormat, Setting};
+
+mod conversions;
+use conversions::ConversionLimiter;
+
+mod custom_src;
+
+mod download;
+use download::DownloadLimits;
+
+mod fact;
+
+mod gallery;
+
+mod geo;
+use geo::{AnalyticsLogger, Geolocator, VisitRecorder};
+
+mod limits;
+use limits::RequestLimits;
+
+mod maintenance;
+use maintenance::{Maintenance, MaintenanceState};
+
+mod metrics;
+use metrics::Metrics;
+
+mod negotiate;
+
+mod ratelimit;
+use ratelimit::RateLimiter;
+
+mod recent_errors;
+use recent_errors::{RecentError, RecentErrors};
+
+mod request_id;
+
+mod sampling;
+use sampling::TraceSampler;
+
+mod source;
+use source::{Animal, ImageQuery, ImageSource, TheApiSource};
+
+mod svg;
+
+mod telemetry;
+
+mod transcode;
+use transcode::Encoding;
+
+mod user_agent;
 
 #[derive(Clone)]
 struct ServerState {
     client: reqwest::Client,
-    locat: Arc<Locat>,
+    /// `None` when the databases couldn't be opened: we still serve cats,
+    /// but without geolocation or analytics.
+    locat: Option<Arc<Geolocator>>,
+    /// `Some` whenever `locat` is
+    visits: Option<Arc<VisitRecorder>>,
+    /// Where cats come from
+    image_source: Arc<dyn ImageSource>,
+    dog_source: Arc<dyn ImageSource>,
+    cat_fact_url: Arc<str>,
+    /// One per source, so dogs failing doesn't stop the cats
+    cat_breaker: Arc<CircuitBreaker>,
+    dog_breaker: Arc<CircuitBreaker>,
+    art_cache: Arc<ArtCache>,
+    metrics: Arc<Metrics>,
+    rate_limiter: Arc<RateLimiter>,
+    download_limits: Arc<DownloadLimits>,
+    request_limits: Arc<RequestLimits>,
+    conversions: Arc<ConversionLimiter>,
+    recent_errors: Arc<RecentErrors>,
+    /// For `GET /debug/config`, secrets redacted already
+    settings: Arc<BTreeMap<String, Setting>>,
+    /// Serve [FALLBACK_CAT] rather than an error when upstream is down
+    fallback_cat: bool,
+    /// Whether `?src=` may be used, from `$ALLOW_CUSTOM_SRC`
+    custom_src: bool,
+    /// Checked against [trusted_client_addr]'s country
+    blocked_countries: Arc<[String]>,
+    /// From `$TRUST_FLY_CLIENT_IP`, for [trusted_client_addr]
+    trust_fly_client_ip: bool,
+    admin_token: Arc<AdminToken>,
+    maintenance: Arc<Maintenance>,
+    started_at: Instant,
+    trace_sampler: TraceSampler,
+    /// See [within_budget]
+    request_budget: Duration,
+    /// Paths [access_log] skips
+    access_log_excluded: Arc<[String]>,
+}
+
+impl ServerState {
+    fn source_for(&self, animal: Animal) -> (&dyn ImageSource, &CircuitBreaker) {
+        match animal {
+            Animal::Cat => (self.image_source.as_ref(), &self.cat_breaker),
+            Animal::Dog => (self.dog_source.as_ref(), &self.dog_breaker),
+        }
+    }
+
+    /// Everything but geolocation, which [open_geolocator] takes care of
+    fn new(config: &Config) -> Self {
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("catscii/", env!("CARGO_PKG_VERSION")))
+            .timeout(config.http_timeout)
+            .connect_timeout(config.http_connect_timeout)
+            .build()
+            .expect("reqwest client should build");
+        let breaker = || {
+            CircuitBreaker::new(
+                config.circuit_breaker_threshold,
+                config.circuit_breaker_cooldown,
+            )
+        };
+
+        Self {
+            client,
+            locat: None,
+            visits: None,
+            image_source: config.image_source.build(&config.catapi),
+            dog_source: Arc::new(TheApiSource::dogs(&config.dogapi)),
+            cat_fact_url: config.cat_fact_url.as_str().into(),
+            cat_breaker: Arc::new(breaker()),
+            dog_breaker: Arc::new(breaker()),
+            art_cache: Arc::new(ArtCache::new(
+                config.art_cache_capacity,
+                config.art_cache_ttl,
+            )),
+            metrics: Arc::new(Metrics::new()),
+            rate_limiter: Arc::new(RateLimiter::new(
+                config.rate_limit_rpm,
+                config.trust_fly_client_ip,
+            )),
+            download_limits: Arc::new(config.download_limits.clone()),
+            request_limits: Arc::new(config.request_limits.clone()),
+            conversions: Arc::new(ConversionLimiter::new(
+                config.max_concurrent_conversions,
+                config.conversion_queue_timeout,
+            )),
+            recent_errors: Default::default(),
+            settings: Arc::new(config.settings.clone()),
+            fallback_cat: config.fallback_cat,
+            custom_src: config.allow_custom_src,
+            blocked_countries: config.blocked_countries.clone().into(),
+            trust_fly_client_ip: config.trust_fly_client_ip,
+            admin_token: Arc::new(AdminToken::new(config.admin_token.clone())),
+            maintenance: Arc::new(Maintenance::new(config.maintenance_retry_after)),
+            started_at: Instant::now(),
+            trace_sampler: TraceSampler::new(config.trace_sample_rate),
+            request_budget: config.request_budget,
+            access_log_excluded: config.access_log_excluded.clone().into(),
+        }
+    }
+}
+
+/// Opens the GeoLite2 and analytics databases, warning (once, here) if
+/// that's not possible.
+fn open_geolocator(config: &Config) -> Option<Geolocator> {
+    let Some(country_db_path) = &config.geolite2_country_db else {
+        warn!("$GEOLITE2_COUNTRY_DB is not set, geolocation and analytics are disabled");
+        return None;
+    };
+    println!("{country_db_path}");
+
+    let analytics: Arc<dyn AnalyticsStore> = match config.analytics_backend {
+        AnalyticsBackend::Sqlite => Arc::new(open_sqlite_store(config)?),
+        AnalyticsBackend::Memory => {
+            info!("Counting visits in memory, they won't survive a restart");
+            Arc::new(MemoryStore::default())
+        }
+    };
+    match maxminddb::Reader::open_readfile(country_db_path) {
+        Ok(countries) => Some(Geolocator::new(
+            analytics,
+            country_db_path.clone(),
+            countries,
+            open_city_db(config),
+            config.geo_cache_capacity,
+        )),
+        Err(e) => {
+            warn!(
+                "Could not open geolocation databases, geolocation and analytics are disabled: {e}"
+            );
+            None
+        }
+    }
+}
+
+/// Opens the SQLite analytics DB
+fn open_sqlite_store(config: &Config) -> Option<SqliteStore> {
+    let analytics_db_env_var = "ANALYTICS_DB";
+    let Some(analytics_db_path) = &config.analytics_db else {
+        warn!("${analytics_db_env_var} is not set, geolocation and analytics are disabled");
+        return None;
+    };
+    println!("{analytics_db_path}");
+
+    // an analytics DB that was asked for but can't be written is a
+    // deployment mistake, not something to quietly run without
+    if let Err(e) = prepare_analytics_db(std::path::Path::new(&analytics_db_path)) {
+        error!(
+            "${analytics_db_env_var} is set to {analytics_db_path:?}, which can't be written \
+             ({e}). Point it at a writable path, or check the directory's owner and permissions."
+        );
+        std::process::exit(1);
+    }
+
+    match SqliteStore::open(
+        analytics_db_path.clone(),
+        config.analytics_reconnect_retries,
+    ) {
+        Ok(store) => Some(store),
+        Err(e) => {
+            warn!("Could not open the analytics DB, geolocation and analytics are disabled: {e}");
+            None
+        }
+    }
+}
+
+/// Opens the GeoLite2-City database, if there's one. Without it, we only
+/// know countries, so failing is only worth a warning.
+fn open_city_db(config: &Config) -> Option<maxminddb::Reader<Vec<u8>>> {
+    let path = config.geolite2_city_db.as_ref()?;
+    match maxminddb::Reader::open_readfile(path) {
+        Ok(cities) => Some(cities),
+        Err(e) => {
+            warn!("Could not open $GEOLITE2_CITY_DB ({path:?}), cities are disabled: {e}");
+            None
+        }
+    }
+}
+
+/// Creates the analytics DB's parent directories if needed, then makes sure
+/// the file itself can be opened for writing
+fn prepare_analytics_db(path: &std::path::Path) -> std::io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    let (_honeyguard, _tracer) = opentelemetry_honeycomb::new_pipeline(
-        std::env::var("HONEYCOMB_API_KEY").expect("$HONEYCOMB_API_KEY should be set"),
-        "catscii".into(),
-    )
-    .install()
-    .unwrap();
+    match std::env::args().nth(1).as_deref() {
+        None => serve().await,
+        Some("oneshot") => oneshot().await,
+        Some(command) => {
+            eprintln!("Unknown command {command:?}, try `catscii oneshot`");
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Reads the [Config], or explains what's wrong with it and quits
+fn load_config() -> Config {
+    match Config::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e:?}");
+            std::process::exit(1);
+        }
+    }
+}
 
-    let filter = Targets::from_str(std::env::var("RUST_LOG").as_deref().unwrap_or("info"))
-        .expect("RUST_LOG should be a valid tracing filter");
+/// Prints a single cat to stdout, colored for terminals, without starting
+/// the server or exporting traces
+async fn oneshot() {
+    let config = load_config();
+    // stdout is for the cat
     tracing_subscriber::fmt()
-        .with_max_level(Level::TRACE)
-        .json()
+        .with_writer(std::io::stderr)
         .finish()
-        .with(filter)
+        .with(config.log_filter.clone())
         .init();
 
-    let country_db_env_var = "GEOLITE2_COUNTRY_DB";
-    let country_db_path = std::env::var(country_db_env_var)
-        .unwrap_or_else(|_| panic!("${country_db_env_var} must be set"));
-    println!("{country_db_path}");
+    let state = ServerState::new(&config);
+    let options = RenderOptions {
+        format: ArtFormat::Ansi,
+        ..RenderOptions::default()
+    };
+    match get_cat_ascii_art(&state, &ImageQuery::default(), options).await {
+        Ok(art) => println!("{}", art.body),
+        Err(e) => {
+            eprintln!("Could not get a cat: {e:?}");
+            std::process::exit(1);
+        }
+    }
+}
 
-    let analytics_db_env_var = "ANALYTICS_DB";
-    let analytics_db_path = std::env::var(analytics_db_env_var)
-        .unwrap_or_else(|_| panic!("${analytics_db_env_var} must be set"));
-    println!("{analytics_db_path}");
+/// Runs the server, which is what happens without a command
+async fn serve() {
+    let config = load_config();
+    // artem colors ANSI art with `colored`, which turns itself off when our
+    // stdout isn't a terminal. It's the client's terminal that matters.
+    colored::control::set_override(true);
+
+    // without a key, spans go to the default no-op tracer, so the app still
+    // runs (and logs) locally
+    let honeyguard = config.honeycomb_api_key.clone().map(|api_key| {
+        let (honeyguard, _tracer) =
+            opentelemetry_honeycomb::new_pipeline(api_key, "catscii".into())
+                .install()
+                .unwrap();
+        honeyguard
+    });
+
+    // each format is its own subscriber type, hence one `init` per arm.
+    // `$RUST_LOG` filters all of them the same way.
+    let fmt = tracing_subscriber::fmt().with_max_level(Level::TRACE);
+    let filter = config.log_filter.clone();
+    match config.log_format {
+        LogFormat::Json => fmt.json().finish().with(filter).init(),
+        LogFormat::Pretty => fmt.pretty().finish().with(filter).init(),
+        LogFormat::Compact => fmt.compact().finish().with(filter).init(),
+    }
+    if honeyguard.is_none() {
+        warn!("$HONEYCOMB_API_KEY is not set, traces will not be exported");
+    }
+
+    let locat = open_geolocator(&config).map(Arc::new);
+    let visits = locat.clone().map(|geolocator| {
+        Arc::new(VisitRecorder::spawn(
+            geolocator,
+            config.analytics_batch_size,
+            config.analytics_flush_interval,
+        ))
+    });
+    let analytics_log_interval = config.analytics_log_interval;
+    let analytics_logger = locat
+        .clone()
+        .filter(|_| !analytics_log_interval.is_zero())
+        .map(|geolocator| AnalyticsLogger::spawn(geolocator, analytics_log_interval));
+    #[cfg(unix)]
+    if let Some(geolocator) = &locat {
+        geo::log_analytics_on_sigusr1(geolocator.clone());
+    }
 
     let state = ServerState {
-        client: Default::default(),
-        locat: Arc::new(Locat::new(&country_db_path, &analytics_db_path).unwrap()),
+        locat: locat.clone(),
+        visits: visits.clone(),
+        ..ServerState::new(&config)
     };
+    telemetry::handle_errors(state.metrics.clone());
 
-    let app = Router::new()
-        .route("/", get(root_get))
-        .route("/analytics", get(analytics_get))
-        .route("/panic", get(|| async { panic!("This is a test panic") }))
-        .with_state(state);
+    let warmup_state = config.warmup_on_start.then(|| state.clone());
+    let app = build_router(state, config.allowed_origins.clone().map(cors_layer));
 
-    let quit_sig = async {
-        _ = tokio::signal::ctrl_c().await;
+    let addr = config.listen_addr;
+    let listener = std::net::TcpListener::bind(addr)
+        .unwrap_or_else(|e| panic!("Could not listen on {addr}: {e}"));
+    info!("Listening on {addr}");
+    if let Some(state) = warmup_state {
+        tokio::spawn(warm_up(state));
+    }
+    run_server(listener, app, shutdown_signal(), config.shutdown_timeout).await;
+
+    if let Some(analytics_logger) = analytics_logger {
+        analytics_logger.shutdown().await;
+    }
+    if let Some(visits) = visits {
+        visits.shutdown().await;
+        info!("Recorded pending visits");
+    }
+}
+
+/// Draws one cat the way `/` would by default, so the first real request
+/// finds the connection pools warm (and maybe its art cached). Failing is
+/// only worth a warning.
+async fn warm_up(state: ServerState) {
+    let options = RenderOptions::default();
+    let start = Instant::now();
+    let res = get_cat_ascii_art(&state, &ImageQuery::default(), options)
+        .with_context(Context::current_with_span(
+            global::tracer("").start("warm_up"),
+        ))
+        .await;
+    match res {
+        Ok(_) => info!("Warmed up in {:?}", start.elapsed()),
+        Err(e) => warn!("Could not warm up, carrying on: {e:?}"),
+    }
+}
+
+/// Serves `app` until `shutdown` resolves. New connections are refused from
+/// then on, and in-flight requests get `shutdown_timeout` to finish before
+/// we give up on them.
+async fn run_server(
+    listener: std::net::TcpListener,
+    app: Router,
+    shutdown: impl Future<Output = ()>,
+    shutdown_timeout: Duration,
+) {
+    let (drain_tx, drain_rx) = tokio::sync::oneshot::channel();
+    let quit_sig = async move {
+        shutdown.await;
         warn!("Initiating graceful shutdown");
+        _ = drain_tx.send(Instant::now());
     };
 
-    let addr = "0.0.0.0:8080".parse().unwrap();
-    info!("Listening on {addr}");
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(quit_sig)
-        .await
-        .unwrap();
+    listener
+        .set_nonblocking(true)
+        .expect("listener should be made non-blocking");
+    let server = axum::Server::from_tcp(listener)
+        .expect("listener should be usable by hyper")
+        // the rate limiter falls back on the peer address
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(quit_sig);
+    tokio::pin!(server);
+
+    let drain_start = tokio::select! {
+        res = &mut server => return res.unwrap(),
+        Ok(start) = drain_rx => start,
+    };
+    match tokio::time::timeout(shutdown_timeout, server).await {
+        Ok(res) => {
+            res.unwrap();
+            info!(
+                "Drained connections in {:.1}s",
+                drain_start.elapsed().as_secs_f64()
+            );
+        }
+        Err(_) => warn!(
+            "Connections still open after {}s, forcing exit",
+            shutdown_timeout.as_secs()
+        ),
+    }
 }
 
-async fn analytics_get(State(state): State<ServerState>) -> Response<BoxBody> {
-    let analytics = state.locat.get_analytics().await.unwrap();
-    let mut response = String::new();
-    use std::fmt::Write;
-    for (country, count) in analytics {
-        _ = writeln!(&mut response, "{country}: {count}");
+/// All routes, with their middleware
+fn build_router(state: ServerState, cors: Option<CorsLayer>) -> Router {
+    // what browser dashboards may fetch from other origins, preflight
+    // requests included
+    let mut analytics = Router::new()
+        .route("/analytics", get(analytics_get))
+        .route("/analytics.json", get(analytics_json_get));
+    if let Some(cors) = cors {
+        analytics = analytics.layer(cors);
     }
-    response.into_response()
+
+    // everything that calls upstream, which maintenance mode turns off
+    let cats = Router::new()
+        .route("/", get(root_get))
+        .route("/cat.txt", get(cat_txt_get))
+        .route("/gallery", get(gallery_get))
+        .route("/fancy", get(fancy_get))
+        .route("/url", get(url_get))
+        .route_layer(middleware::from_fn_with_state(
+            state.rate_limiter.clone(),
+            ratelimit::limit,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.maintenance.clone(),
+            maintenance::guard,
+        ));
+
+    Router::new()
+        .merge(cats)
+        .merge(analytics)
+        .route(
+            "/geoip/:ip",
+            get(geoip_get).route_layer(middleware::from_fn_with_state(
+                state.admin_token.clone(),
+                admin::require_configured_token,
+            )),
+        )
+        .route(
+            "/geoip/batch",
+            post(geoip_batch_post).route_layer(middleware::from_fn_with_state(
+                state.admin_token.clone(),
+                admin::require_configured_token,
+            )),
+        )
+        .route(
+            "/admin/analytics/export",
+            get(analytics_export_get).route_layer(middleware::from_fn_with_state(
+                state.admin_token.clone(),
+                admin::require_configured_token,
+            )),
+        )
+        .route(
+            "/analytics/reset",
+            post(analytics_reset_post).route_layer(middleware::from_fn_with_state(
+                state.admin_token.clone(),
+                admin::require_configured_token,
+            )),
+        )
+        .route(
+            "/admin/errors",
+            get(errors_get).route_layer(middleware::from_fn_with_state(
+                state.admin_token.clone(),
+                admin::require_configured_token,
+            )),
+        )
+        .route(
+            "/debug/config",
+            get(config_get).route_layer(middleware::from_fn_with_state(
+                state.admin_token.clone(),
+                admin::require_configured_token,
+            )),
+        )
+        .route(
+            "/admin/reload-geoip",
+            post(reload_geoip_post).route_layer(middleware::from_fn_with_state(
+                state.admin_token.clone(),
+                admin::require_configured_token,
+            )),
+        )
+        .route(
+            "/admin/maintenance",
+            post(maintenance_post).route_layer(middleware::from_fn_with_state(
+                state.admin_token.clone(),
+                admin::require_configured_token,
+            )),
+        )
+        .route("/favicon.ico", get(favicon_get))
+        .route("/static/*path", get(static_get))
+        .route("/healthz", get(healthz_get))
+        .route("/healthz/deep", get(healthz_deep_get))
+        .route("/metrics", get(metrics_get))
+        .route("/openapi.json", get(openapi_get))
+        .route("/status", get(status_get))
+        .route("/panic", get(|| async { panic!("This is a test panic") }))
+        .route_layer(middleware::from_fn_with_state(
+            state.metrics.clone(),
+            metrics::track,
+        ))
+        .fallback(not_found)
+        .layer(middleware::from_fn_with_state(
+            state.request_limits.clone(),
+            limits::enforce,
+        ))
+        // for bodies without a `Content-Length`, which `limits::enforce`
+        // can't check up front
+        .layer(DefaultBodyLimit::max(state.request_limits.max_body_bytes))
+        .layer(middleware::from_fn(error::method_not_allowed))
+        // inside `propagate`, so lines have the request ID
+        .layer(middleware::from_fn_with_state(
+            Arc::new(AccessLog::new(
+                state.access_log_excluded.to_vec(),
+                state.locat.clone(),
+            )),
+            access_log::log,
+        ))
+        .layer(middleware::from_fn(request_id::propagate))
+        // art is mostly the same few color spans over and over
+        .layer(CompressionLayer::new())
+        .with_state(state)
 }
 
-fn get_client_addr(headers: &HeaderMap) -> Option<IpAddr> {
-    let header = headers.get("fly-client-ip")?;
-    let header = header.to_str().ok()?;
-    let addr = header.parse::<IpAddr>().ok()?;
-    Some(addr)
+/// CORS for the analytics routes, letting `allow_origin` read them from
+/// browsers
+fn cors_layer(allow_origin: AllowOrigin) -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET])
+        .expose_headers([HeaderName::from_static(TOTAL_COUNT_HEADER)])
 }
 
-async fn root_get(headers: HeaderMap, State(state): State<ServerState>) -> Response<BoxBody> {
-    let tracer = global::tracer("");
-    let mut span = tracer.start("root_get");
-    span.set_attribute(KeyValue::new(
-        "user_agent",
-        headers
-            .get(header::USER_AGENT)
-            .map(|h| h.to_str().unwrap_or_default().to_owned())
-            .unwrap_or_default(),
-    ));
+/// Resolves on SIGINT (ctrl-c) or SIGTERM, which is what containers get sent
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        _ = tokio::signal::ctrl_c().await;
+    };
 
-    if let Some(addr) = get_client_addr(&headers) {
-        match state.locat.ip_to_iso_code(addr).await {
-            Some(country) => {
-                info!("Got request from {country}");
-                span.set_attribute(KeyValue::new("country", country.to_string()));
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
             }
-            None => warn!("Could not determine country for IP address"),
+            Err(e) => {
+                warn!("Could not listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+const NOT_FOUND_CAT: &str = r#" /\_/\
+( o.o )  nothing here,
+ > ^ <   try / instead
+"#;
+
+/// Fallback for unknown routes, a JSON error for API consumers and a small
+/// cat for everyone else
+async fn not_found(headers: HeaderMap) -> Response<BoxBody> {
+    let accept = headers.get(header::ACCEPT).and_then(|h| h.to_str().ok());
+    if error::wants_json(accept) {
+        return ApiError {
+            code: ErrorCode::NotFound,
+            json: true,
         }
+        .into_response();
     }
 
-    root_get_inner(state)
-        .with_context(Context::current_with_span(span))
-        .await
+    let format = ArtFormat::negotiate(accept);
+    (
+        StatusCode::NOT_FOUND,
+        [
+            (header::CONTENT_TYPE, format.content_type()),
+            (header::VARY, "accept"),
+        ],
+        art::from_plain(NOT_FOUND_CAT, format),
+    )
+        .into_response()
 }
-//               to here 👇
-async fn root_get_inner(state: ServerState) -> Response<BoxBody> {
-    let tracer = global::tracer("");
 
-    //       passing the client 👇
-    match get_cat_ascii_art(&state.client)
-        .with_context(Context::current_with_span(
-            tracer.start("get_cat_ascii_art"),
-        ))
-        .await
-    {
-        Ok(art) => (
-            StatusCode::OK,
-            [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
-            art,
-        )
-            .into_response(),
+/// The last [recent_errors::CAPACITY] errors cats were served with, most
+/// recent first. Fallback cats count.
+async fn errors_get(State(state): State<ServerState>) -> Json<Vec<RecentError>> {
+    Json(state.recent_errors.list())
+}
+
+/// Every variable the configuration was read from, and whether it was set
+async fn config_get(State(state): State<ServerState>) -> Json<BTreeMap<String, Setting>> {
+    Json(state.settings.as_ref().clone())
+}
+
+/// Reads the GeoLite2 country database again, without a restart. Requests
+/// keep being geolocated throughout, with the old one until the new one is
+/// in, or for good if it can't be opened.
+async fn reload_geoip_post(State(state): State<ServerState>) -> Response<BoxBody> {
+    let Some(locat) = &state.locat else {
+        return ApiError {
+            code: ErrorCode::GeolocationUnavailable,
+            json: true,
+        }
+        .into_response();
+    };
+    let locat = locat.clone();
+    match spawn_blocking(move || locat.reload_countries()).await {
+        Ok(()) => {
+            info!("Reloaded the GeoLite2 country database");
+            Json(Health {
+                status: "ok",
+                error: None,
+            })
+            .into_response()
+        }
         Err(e) => {
-            get_active_span(|span| {
-                span.set_status(Status::Error {
-                    description: format!("{e}").into(),
-                })
-            });
-            (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong").into_response()
+            error!("Could not reload the GeoLite2 country database, keeping the old one: {e:#}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(Health {
+                    status: "error",
+                    error: Some(format!("{e:#}")),
+                }),
+            )
+                .into_response()
         }
     }
 }
 
-//                   to here 👇
-async fn get_cat_ascii_art(client: &reqwest::Client) -> color_eyre::Result<String> {
-    let tracer = global::tracer("");
+/// Turns maintenance mode on or off, with `{"enabled": bool}`
+async fn maintenance_post(
+    State(state): State<ServerState>,
+    Json(body): Json<MaintenanceState>,
+) -> Json<MaintenanceState> {
+    state.maintenance.set_enabled(body.enabled);
+    warn!(
+        "Maintenance mode {}",
+        if body.enabled { "enabled" } else { "disabled" }
+    );
+    Json(body)
+}
 
-    //   and then our helper functions 👇
-    let image_url = get_cat_image_url(client)
-        .with_context(Context::current_with_span(
-            tracer.start("get_cat_image_url"),
-        ))
-        .await?;
+/// Describes the routes in [build_router], see `test_openapi` for keeping
+/// the two in sync
+const OPENAPI: &str = include_str!("openapi.json");
 
-    let image_bytes = download_file(client, &image_url)
-        .with_context(Context::current_with_span(tracer.start("download_file")))
-        .await?;
+async fn openapi_get() -> Response<BoxBody> {
+    ([(header::CONTENT_TYPE, "application/json")], OPENAPI).into_response()
+}
 
-    let image = tracer.in_span("image::load_from_memory", |cx| {
-        let img = image::load_from_memory(&image_bytes)?;
-        cx.span()
-            .set_attribute(KeyValue::new("width", img.width() as i64));
-        cx.span()
-            .set_attribute(KeyValue::new("height", img.height() as i64));
-        Ok::<_, color_eyre::eyre::Report>(img)
-    })?;
+async fn favicon_get() -> Response<BoxBody> {
+    assets::find("favicon.ico")
+        .expect("the favicon should be embedded")
+        .into_response()
+}
 
-    let ascii_art = tracer.in_span("artem::convert", |_cx| {
-        artem::convert(
-            image,
-            artem::options::OptionBuilder::new()
-                .target(artem::options::TargetType::HtmlFile(true, true))
-                .build(),
-        )
-    });
+async fn static_get(Path(path): Path<String>, headers: HeaderMap) -> Response<BoxBody> {
+    match assets::find(&path) {
+        Some(asset) => asset.into_response(),
+        None => not_found(headers).await,
+    }
+}
 
-    Ok(ascii_art)
+#[derive(serde::Serialize)]
+struct ProcessStatus {
+    uptime_secs: u64,
+    requests_served: u64,
+    geolocation: bool,
+    art_cache: CacheStats,
 }
 
-async fn get_cat_image_url(client: &reqwest::Client) -> color_eyre::Result<String> {
-    #[derive(serde::Deserialize)]
-    struct CatImage {
-        url: String,
+/// A quick look at how the process is doing, without going to Honeycomb
+async fn status_get(State(state): State<ServerState>) -> Json<ProcessStatus> {
+    Json(ProcessStatus {
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        requests_served: state.metrics.served(),
+        geolocation: state.locat.is_some(),
+        art_cache: state.art_cache.stats(),
+    })
+}
+
+async fn metrics_get(State(state): State<ServerState>) -> Response<BoxBody> {
+    state.metrics.render()
+}
+
+/// Fetches the all-time counts, or those from `since` on, or else the error
+/// response to send
+async fn get_analytics(
+    state: &ServerState,
+    since: Option<&str>,
+    json: bool,
+) -> Result<Vec<(String, u64)>, ApiError> {
+    let Some(locat) = &state.locat else {
+        return Err(ApiError {
+            code: ErrorCode::AnalyticsUnavailable,
+            json,
+        });
+    };
+    let analytics = match since.map(str::trim) {
+        None | Some("") => locat.get_analytics().await,
+        Some(since) => match parse_since(since, SystemTime::now()) {
+            Some(since) => locat.get_analytics_since(since).await,
+            None => {
+                return Err(ApiError {
+                    code: ErrorCode::InvalidSince,
+                    json,
+                })
+            }
+        },
+    };
+    let mut analytics = analytics.map_err(|e| {
+        warn!("Could not get analytics: {e}");
+        ApiError {
+            code: ErrorCode::AnalyticsUnavailable,
+            json,
+        }
+    })?;
+    // most requests first, then alphabetically so the order is stable
+    analytics.sort_by(|(a_country, a_count), (b_country, b_count)| {
+        b_count.cmp(a_count).then_with(|| a_country.cmp(b_country))
+    });
+    Ok(analytics)
+}
+
+/// When `?since=` starts: a duration back from `now` like `24h` or `30m`,
+/// or an RFC 3339 timestamp
+fn parse_since(since: &str, now: SystemTime) -> Option<SystemTime> {
+    if let Ok(ago) = humantime::parse_duration(since) {
+        return now.checked_sub(ago);
+    }
+    // humantime only takes UTC, offsets are applied here
+    let split = since.len().checked_sub(6)?;
+    let (Some(time), Some(offset)) = (since.get(..split), since.get(split..)) else {
+        return None;
+    };
+    let sign = offset.chars().next();
+    if !matches!(sign, Some('+' | '-')) || offset.as_bytes()[3] != b':' {
+        return humantime::parse_rfc3339(since).ok();
+    }
+    let hours: u64 = offset[1..3].parse().ok()?;
+    let minutes: u64 = offset[4..].parse().ok()?;
+    let offset = Duration::from_secs(hours * 3600 + minutes * 60);
+    let time = humantime::parse_rfc3339(&format!("{time}Z")).ok()?;
+    match sign {
+        Some('+') => time.checked_sub(offset),
+        _ => time.checked_add(offset),
     }
+}
+
+/// How many countries the analytics routes list when `?limit=` is absent or
+/// unparseable
+const DEFAULT_ANALYTICS_LIMIT: usize = 50;
 
-    let api_url = "https://api.thecatapi.com/v1/images/search";
+/// How many countries there are in all, when only a page of them is listed
+const TOTAL_COUNT_HEADER: &str = "x-total-count";
 
-    let image = client
-        .get(api_url)
-        .send()
-        .await?
-        .error_for_status()?
-        .json::<Vec<CatImage>>()
-        .await?
-        .pop()
-        .ok_or_else(|| color_eyre::eyre::eyre!("The Cat API returned no images"))?;
-    Ok(image.url)
+#[derive(serde::Deserialize)]
+struct AnalyticsParams {
+    limit: Option<String>,
+    offset: Option<String>,
+    /// `csv` for spreadsheets, overriding content negotiation
+    format: Option<String>,
+    /// Only visits since then, see [parse_since]
+    since: Option<String>,
 }
 
-async fn download_file(client: &reqwest::Client, url: &str) -> color_eyre::Result<Vec<u8>> {
-    let bytes = client
-        .get(url)
-        .send()
-        .await?
-        .error_for_status()?
-        .bytes()
-        .await?;
-    Ok(bytes.to_vec())
+impl AnalyticsParams {
+    /// The part of `counts` that was asked for
+    fn page<'a, T>(&self, counts: &'a [T]) -> &'a [T] {
+        let parse = |value: &Option<String>| {
+            value
+                .as_deref()
+                .and_then(|value| value.trim().parse::<usize>().ok())
+        };
+        let offset = parse(&self.offset).unwrap_or(0).min(counts.len());
+        let limit = parse(&self.limit).unwrap_or(DEFAULT_ANALYTICS_LIMIT);
+        let counts = &counts[offset..];
+        &counts[..limit.min(counts.len())]
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+async fn analytics_get(
+    headers: HeaderMap,
+    Query(params): Query<AnalyticsParams>,
+    State(state): State<ServerState>,
+) -> Response<BoxBody> {
+    let analytics = match get_analytics(&state, params.since.as_deref(), false).await {
+        Ok(analytics) => analytics,
+        Err(e) => return e.into_response(),
+    };
+    let total = [(TOTAL_COUNT_HEADER, analytics.len().to_string())];
+    let csv = match params.format.as_deref().map(str::trim) {
+        Some(format) => format == "csv",
+        None => {
+            let accept = headers.get(header::ACCEPT).and_then(|h| h.to_str().ok());
+            negotiate::preferred(accept, &["text/plain", "text/csv"]) == Some("text/csv")
+        }
+    };
 
-    #[test]
-    fn test_db() {
-        let country_db_env_var = "GEOLITE2_COUNTRY_DB";
-        let country_db_path = std::env::var(country_db_env_var)
-            .unwrap_or_else(|_| panic!("${country_db_env_var} must be set"));
+    let mut response = String::new();
+    use std::fmt::Write;
+    if csv {
+        response.push_str("country,count\r\n");
+        for (country, count) in params.page(&analytics) {
+            _ = write!(&mut response, "{},{count}\r\n", csv_field(country));
+        }
+        return (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    r#"attachment; filename="analytics.csv""#,
+                ),
+                (header::VARY, "accept"),
+            ],
+            total,
+            response,
+        )
+            .into_response();
+    }
 
-        let analytics_db_env_var = "ANALYTICS_DB";
-        let analytics_db_path = std::env::var(analytics_db_env_var)
-            .unwrap_or_else(|_| panic!("${analytics_db_env_var} must be set"));
-        let _locat = Locat::new(&country_db_path, &analytics_db_path).unwrap();
+    for (country, count) in params.page(&analytics) {
+        _ = writeln!(&mut response, "{country}: {count}");
+    }
+    ([(header::VARY, "accept")], total, response).into_response()
+}
 
-        println!("{country_db_path:?}");
-        println!("{analytics_db_path:?}");
+/// Quotes `value` if it needs quoting in CSV. Spreadsheets run anything
+/// that starts like a formula, so that gets defused too.
+fn csv_field(value: &str) -> std::borrow::Cow<'_, str> {
+    let formula = value.starts_with(['=', '+', '-', '@', '\t', '\r']);
+    if !formula && !value.contains([',', '"', '\n', '\r']) {
+        return value.into();
+    }
+    let value = if formula {
+        format!("'{value}")
+    } else {
+        value.to_owned()
+    };
+    format!("\"{}\"", value.replace('"', "\"\"")).into()
+}
+
+#[derive(serde::Serialize)]
+struct CountryCount {
+    country: String,
+    count: u64,
+}
+
+async fn analytics_json_get(
+    Query(params): Query<AnalyticsParams>,
+    State(state): State<ServerState>,
+) -> Response<BoxBody> {
+    let analytics = match get_analytics(&state, params.since.as_deref(), true).await {
+        Ok(analytics) => analytics,
+        Err(e) => return e.into_response(),
+    };
+    let counts: Vec<CountryCount> = params
+        .page(&analytics)
+        .iter()
+        .map(|(country, count)| CountryCount {
+            country: country.clone(),
+            count: *count,
+        })
+        .collect();
+    (
+        [(TOTAL_COUNT_HEADER, analytics.len().to_string())],
+        Json(counts),
+    )
+        .into_response()
+}
+
+/// Forgets every visit, for tests and privacy requests. Visits still queued
+/// in the [VisitRecorder] count afterwards.
+async fn analytics_reset_post(State(state): State<ServerState>) -> Response<BoxBody> {
+    let Some(locat) = &state.locat else {
+        return ApiError {
+            code: ErrorCode::AnalyticsUnavailable,
+            json: true,
+        }
+        .into_response();
+    };
+    match locat.clear_analytics().await {
+        Ok(()) => {
+            warn!("Analytics were reset");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => {
+            warn!("Could not reset analytics: {e}");
+            ApiError {
+                code: ErrorCode::AnalyticsUnavailable,
+                json: true,
+            }
+            .into_response()
+        }
+    }
+}
+
+/// Every country as newline-delimited JSON, for backups. The store only hands
+/// out the counts as a whole, so this works from that snapshot: the database
+/// isn't held while the response goes out.
+async fn analytics_export_get(State(state): State<ServerState>) -> Response<BoxBody> {
+    let analytics = match get_analytics(&state, None, true).await {
+        Ok(analytics) => analytics,
+        Err(e) => return e.into_response(),
+    };
+    let mut body = String::new();
+    for (country, count) in analytics {
+        let line = serde_json::to_string(&CountryCount { country, count })
+            .expect("a country count always serializes");
+        body.push_str(&line);
+        body.push('\n');
+    }
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"analytics.ndjson\"",
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+#[derive(serde::Serialize)]
+struct GeoIp {
+    ip: IpAddr,
+    country: String,
+}
+
+/// Where the GeoLite2 database thinks an address is. Doesn't count as a
+/// visit.
+async fn geoip_get(Path(ip): Path<String>, State(state): State<ServerState>) -> Response<BoxBody> {
+    let error = |code| ApiError { code, json: true }.into_response();
+    let Ok(ip) = ip.parse::<IpAddr>() else {
+        return error(ErrorCode::InvalidAddress);
+    };
+    let Some(locat) = &state.locat else {
+        return error(ErrorCode::GeolocationUnavailable);
+    };
+    match locat.iso_code(ip) {
+        Some(country) => Json(GeoIp { ip, country }).into_response(),
+        None => error(ErrorCode::UnknownAddress),
+    }
+}
+
+/// How many addresses `POST /geoip/batch` takes at once
+const GEOIP_BATCH_LIMIT: usize = 1000;
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+struct BatchGeoIp {
+    /// As it was sent, valid or not
+    ip: String,
+    /// `null` for invalid addresses, and ones we have no country for
+    country: Option<String>,
+}
+
+/// [geoip_get] for up to [GEOIP_BATCH_LIMIT] addresses, sent as a JSON
+/// array of strings. Doesn't count as visits either.
+async fn geoip_batch_post(
+    State(state): State<ServerState>,
+    Json(ips): Json<Vec<String>>,
+) -> Response<BoxBody> {
+    let error = |code| ApiError { code, json: true }.into_response();
+    if ips.len() > GEOIP_BATCH_LIMIT {
+        return error(ErrorCode::BatchTooLarge);
+    }
+    let Some(locat) = &state.locat else {
+        return error(ErrorCode::GeolocationUnavailable);
+    };
+    Json(lookup_batch(ips, |addr| locat.iso_code(addr))).into_response()
+}
+
+/// In-memory lookups are quick enough for a plain loop
+fn lookup_batch(ips: Vec<String>, iso_code: impl Fn(IpAddr) -> Option<String>) -> Vec<BatchGeoIp> {
+    ips.into_iter()
+        .map(|ip| {
+            let country = ip.trim().parse().ok().and_then(&iso_code);
+            BatchGeoIp { ip, country }
+        })
+        .collect()
+}
+
+#[derive(serde::Serialize)]
+struct Health {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Readiness probe. The GeoLite2 database is loaded in memory when
+/// geolocation starts, so the part that can actually go away is the analytics DB.
+/// Running without geolocation at all is degraded, but still ready.
+async fn healthz_get(State(state): State<ServerState>) -> Response<BoxBody> {
+    let Some(locat) = &state.locat else {
+        return (
+            StatusCode::OK,
+            Json(Health {
+                status: "degraded",
+                error: Some("geolocation is disabled".into()),
+            }),
+        )
+            .into_response();
+    };
+    match locat.get_analytics().await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(Health {
+                status: "ok",
+                error: None,
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("Health check failed: {e}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(Health {
+                    status: "unavailable",
+                    error: Some(e.to_string()),
+                }),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// How long each dependency gets to answer `/healthz/deep`. They're checked
+/// at the same time, so that's also about how long the whole check takes.
+const DEEP_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// An address every GeoLite2 country database knows about
+const DEEP_CHECK_IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(8, 8, 8, 8));
+
+#[derive(serde::Serialize)]
+struct DeepHealth {
+    status: &'static str,
+    geoip_db: Health,
+    analytics_db: Health,
+    image_source: Health,
+}
+
+/// Runs one `/healthz/deep` check, giving up after [DEEP_CHECK_TIMEOUT]
+async fn deep_check<T, E: std::fmt::Display>(check: impl Future<Output = Result<T, E>>) -> Health {
+    let error = match tokio::time::timeout(DEEP_CHECK_TIMEOUT, check).await {
+        Ok(Ok(_)) => {
+            return Health {
+                status: "ok",
+                error: None,
+            }
+        }
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => format!("timed out after {DEEP_CHECK_TIMEOUT:?}"),
+    };
+    Health {
+        status: "unavailable",
+        error: Some(error),
+    }
+}
+
+/// Like `/healthz`, but actually exercises every dependency, image source
+/// included. That costs an upstream call, so it's meant for occasional
+/// synthetic checks rather than the load balancer.
+async fn healthz_deep_get(State(state): State<ServerState>) -> Response<BoxBody> {
+    let disabled = || Health {
+        status: "degraded",
+        error: Some("geolocation is disabled".into()),
+    };
+    let geoip = async {
+        match &state.locat {
+            Some(locat) => {
+                deep_check(async {
+                    locat
+                        .iso_code(DEEP_CHECK_IP)
+                        .map(|_| ())
+                        .ok_or_else(|| format!("no country found for {DEEP_CHECK_IP}"))
+                })
+                .await
+            }
+            None => disabled(),
+        }
+    };
+    let analytics = async {
+        match &state.locat {
+            Some(locat) => deep_check(locat.get_analytics()).await,
+            None => disabled(),
+        }
+    };
+    // straight to the source: this shouldn't count towards its circuit
+    // breaker, nor be stopped by it
+    let (source, _) = state.source_for(Animal::Cat);
+    let query = ImageQuery::default();
+    let image_source = deep_check(source.fetch_image_url(&state.client, &query));
+    let (geoip_db, analytics_db, image_source) = tokio::join!(geoip, analytics, image_source);
+
+    let checks = [&geoip_db, &analytics_db, &image_source];
+    let (code, status) = if checks.iter().any(|c| c.status == "unavailable") {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    } else if checks.iter().any(|c| c.status == "degraded") {
+        (StatusCode::OK, "degraded")
+    } else {
+        (StatusCode::OK, "ok")
+    };
+    if code != StatusCode::OK {
+        warn!("Deep health check failed");
+    }
+    (
+        code,
+        Json(DeepHealth {
+            status,
+            geoip_db,
+            analytics_db,
+            image_source,
+        }),
+    )
+        .into_response()
+}
+
+/// Headers the client address is read from, in order of precedence: the one
+/// fly.io's proxy sets, then the ones nginx & co. usually do. Good enough to
+/// count visits by, not to block or limit anyone: see [trusted_client_addr].
+const CLIENT_ADDR_HEADERS: &[&str] = &["fly-client-ip", "x-forwarded-for", "x-real-ip"];
+
+fn get_client_addr(headers: &HeaderMap) -> Option<IpAddr> {
+    CLIENT_ADDR_HEADERS.iter().find_map(|name| {
+        let header = headers.get(*name)?.to_str().ok()?;
+        // `X-Forwarded-For` is a list: the leftmost address is the client,
+        // the rest are proxies. Skip anything that doesn't parse.
+        header.split(',').find_map(parse_client_addr)
+    })
+}
+
+/// Parses one address from those headers. Some proxies add the client's
+/// port (`1.2.3.4:1234`, `[::1]:80`) or an IPv6 zone (`fe80::1%eth0`), which
+/// are dropped: neither matters for geolocation.
+fn parse_client_addr(addr: &str) -> Option<IpAddr> {
+    let addr = addr.trim();
+    let host = match addr.strip_prefix('[') {
+        Some(rest) => rest.split_once(']')?.0,
+        // a single colon can't be IPv6, so it's a port
+        None if addr.matches(':').count() == 1 => addr.split_once(':')?.0,
+        None => addr,
+    };
+    let host = host.split_once('%').map_or(host, |(host, _zone)| host);
+    host.parse().ok()
+}
+
+/// The client's address when it decides what the client may do, unlike
+/// [get_client_addr]. That's `Fly-Client-IP` only if `trust_fly` says fly.io's
+/// proxy set it, or else the `peer` the connection came from. Never
+/// `X-Forwarded-For` and the like: anyone can put anything in those.
+fn trusted_client_addr(
+    headers: &HeaderMap,
+    peer: Option<IpAddr>,
+    trust_fly: bool,
+) -> Option<IpAddr> {
+    trust_fly
+        .then(|| headers.get("fly-client-ip")?.to_str().ok())
+        .flatten()
+        .and_then(parse_client_addr)
+        .or(peer)
+}
+
+/// Whether `country` is one of `blocked`, whatever their case
+fn is_blocked(blocked: &[String], country: &str) -> bool {
+    blocked
+        .iter()
+        .any(|blocked| blocked.eq_ignore_ascii_case(country))
+}
+
+#[derive(serde::Deserialize)]
+struct RootParams {
+    // kept as a string so a bad value falls back to the default instead of
+    // failing the whole request with a 400
+    width: Option<String>,
+    breed: Option<String>,
+    /// Overrides content negotiation: `html`, `text` or `svg`, or `json`
+    /// for the HTML art along with where it came from
+    format: Option<String>,
+    /// `false` for monochrome art. Unlike `width`, a typo here is a 400:
+    /// silently getting colors back would be confusing.
+    color: Option<bool>,
+    /// How much taller than wide characters are, like `0.5`. Kept as a
+    /// string like `width`.
+    ratio: Option<String>,
+    /// `true` to swap light and dark, a 400 on typos like `color`
+    invert: Option<bool>,
+    /// Brightness from 0 to 255 under which pixels are drawn black and
+    /// over which white, for stark art. A 400 out of that range.
+    bw_threshold: Option<u8>,
+    /// `true` for art colored with ANSI escapes, as plain text, whatever
+    /// `format` says
+    ansi: Option<bool>,
+    /// `true` to have browsers save the art rather than show it
+    download: Option<bool>,
+    /// `classic`, `blocks`, `dense` or `minimal`
+    style: Option<String>,
+    /// The characters to draw with, darkest first, instead of a `style`
+    charset: Option<String>,
+    /// `low`, `medium` or `high`, for a default width and style
+    quality: Option<String>,
+    /// `cat` or `dog`
+    animal: Option<String>,
+    /// `png`, `jpeg`, `gif` or `webp` for the picture itself, not its art
+    #[serde(rename = "as")]
+    encoding: Option<String>,
+    /// Picks the same image every time, instead of a random one. A typo is
+    /// a 400, like for `color`.
+    seed: Option<u64>,
+    /// Any picture on the web to draw instead of a cat, see [custom_src].
+    /// Art only: `as`, `animal`, `breed` and `seed` are ignored.
+    src: Option<String>,
+}
+
+/// Also answers `HEAD`, see [head_response]
+async fn root_get(
+    method: Method,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    Query(params): Query<RootParams>,
+    State(state): State<ServerState>,
+) -> Response<BoxBody> {
+    let head = method == Method::HEAD;
+    let peer = peer.map(|ConnectInfo(addr)| addr.ip());
+    serve_cat("root_get", head, headers, peer, params, state, None).await
+}
+
+/// Always plain text, whatever the `Accept` header or `?format=` say, for
+/// `curl` and `watch` scripts. `?ansi=true` still colors it.
+async fn cat_txt_get(
+    method: Method,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    Query(params): Query<RootParams>,
+    State(state): State<ServerState>,
+) -> Response<BoxBody> {
+    let head = method == Method::HEAD;
+    let peer = peer.map(|ConnectInfo(addr)| addr.ip());
+    serve_cat(
+        "cat_txt_get",
+        head,
+        headers,
+        peer,
+        params,
+        state,
+        Some(ArtFormat::Text),
+    )
+    .await
+}
+
+/// Parses a query parameter that's one of a few names, falling back to the
+/// default when it's absent. `None` if it's there but unknown.
+fn parse_choice<T: Default>(value: Option<&str>, from_name: fn(&str) -> Option<T>) -> Option<T> {
+    match value.map(str::trim) {
+        None | Some("") => Some(T::default()),
+        Some(name) => from_name(name),
+    }
+}
+
+/// Serves a cat in `format`, or in whatever format the client asked for if
+/// that's `None`. `peer` is who the connection came from, if known.
+async fn serve_cat(
+    span_name: &'static str,
+    head: bool,
+    headers: HeaderMap,
+    peer: Option<IpAddr>,
+    params: RootParams,
+    state: ServerState,
+    format: Option<ArtFormat>,
+) -> Response<BoxBody> {
+    let tracer = global::tracer("");
+    let mut span = tracer.start_with_context(span_name, &state.trace_sampler.root_context());
+    if let Some(id) = request_id::current() {
+        span.set_attribute(KeyValue::new("request_id", id));
+    }
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+    span.set_attribute(KeyValue::new(
+        "user_agent",
+        user_agent::truncate(user_agent).to_owned(),
+    ));
+    span.set_attribute(KeyValue::new(
+        "user_agent_family",
+        user_agent::family(user_agent),
+    ));
+
+    let accept = headers.get(header::ACCEPT).and_then(|h| h.to_str().ok());
+    let json_errors = error::wants_json(accept);
+    let invalid = |code| {
+        ApiError {
+            code,
+            json: json_errors,
+        }
+        .into_response()
+    };
+    let Some(quality) = parse_choice(params.quality.as_deref(), Quality::from_name) else {
+        return invalid(ErrorCode::UnknownQuality);
+    };
+    let style = match params.style.as_deref().map(str::trim) {
+        None | Some("") => quality.style(),
+        Some(name) => match ArtStyle::from_name(name) {
+            Some(style) => style,
+            None => return invalid(ErrorCode::UnknownStyle),
+        },
+    };
+    let style = match params.charset.as_deref() {
+        None => style,
+        Some(charset) => match Charset::parse(charset) {
+            Some(charset) => ArtStyle::Custom(charset),
+            None => return invalid(ErrorCode::InvalidCharset),
+        },
+    };
+    let Some(animal) = parse_choice(params.animal.as_deref(), Animal::from_name) else {
+        return invalid(ErrorCode::UnknownAnimal);
+    };
+    let encoding = match params.encoding.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(name) => match Encoding::from_name(name) {
+            Some(encoding) => Some(encoding),
+            None => return invalid(ErrorCode::UnsupportedEncoding),
+        },
+    };
+    let src = match params.src.as_deref().map(str::trim) {
+        None | Some("") => None,
+        Some(_) if !state.custom_src => return invalid(ErrorCode::CustomSrcDisabled),
+        Some(src) => match custom_src::parse(src) {
+            Ok(url) => Some(url),
+            Err(e) => {
+                warn!("Not drawing {src:?}: {e}");
+                return invalid(ErrorCode::ForbiddenSource);
+            }
+        },
+    };
+
+    // before anything expensive, visits included. By an address the client
+    // can't pick, or `X-Forwarded-For` would get anyone past it.
+    let trusted_addr = trusted_client_addr(&headers, peer, state.trust_fly_client_ip);
+    let blocked = match (&state.locat, trusted_addr) {
+        (Some(locat), Some(addr)) if !state.blocked_countries.is_empty() => {
+            locat.iso_code(addr).map_or(false, |country| {
+                is_blocked(&state.blocked_countries, &country)
+            })
+        }
+        _ => false,
+    };
+    if blocked {
+        span.set_attribute(KeyValue::new("blocked", true));
+        return invalid(ErrorCode::CountryBlocked);
+    }
+
+    let mut client_country = None;
+    if let (Some(locat), Some(addr)) = (&state.locat, get_client_addr(&headers)) {
+        let lookup = locat.lookup(addr);
+        span.set_attribute(KeyValue::new("geo_cache_hit", lookup.cache_hit));
+        match lookup.iso_code {
+            Some(country) => {
+                info!("Got request from {country}");
+                client_country = Some(country.clone());
+                span.set_attribute(KeyValue::new("country", country.to_string()));
+                if let Some(place) = locat.city(addr) {
+                    if let Some(city) = place.city {
+                        span.set_attribute(KeyValue::new("city", city.to_string()));
+                    }
+                    if let Some(subdivision) = place.subdivision {
+                        span.set_attribute(KeyValue::new("subdivision", subdivision.to_string()));
+                    }
+                }
+                if let Some(visits) = &state.visits {
+                    visits.record(addr, country);
+                }
+            }
+            None => warn!("Could not determine country for IP address"),
+        }
+    }
+
+    let query = ImageQuery {
+        animal,
+        breed: params
+            .breed
+            .map(|breed| breed.trim().to_owned())
+            .filter(|breed| !breed.is_empty()),
+        seed: params.seed,
+    };
+    span.set_attribute(KeyValue::new("animal", animal.name()));
+    if let Some(seed) = query.seed {
+        span.set_attribute(KeyValue::new("seed", seed as i64));
+    }
+    if let Some(breed) = &query.breed {
+        span.set_attribute(KeyValue::new("breed", breed.clone()));
+    }
+    if let Some(src) = &src {
+        span.set_attribute(KeyValue::new("src", src.to_string()));
+    }
+
+    if let (Some(encoding), None) = (encoding, &src) {
+        span.set_attribute(KeyValue::new("image_encoding", encoding.name()));
+        if head {
+            span.set_attribute(KeyValue::new("head", true));
+            return head_response(encoding.content_type(), &query);
+        }
+        let cx = Context::current_with_span(span);
+        let res =
+            image_get_inner(state.clone(), query, encoding, json_errors).with_context(cx.clone());
+        return within_budget(&state, &cx, json_errors, res).await;
+    }
+
+    // only on routes that negotiate, `/cat.txt?format=json` is still text
+    let json = (format.is_none() && params.format.as_deref().map(str::trim) == Some("json"))
+        .then_some(JsonArt {
+            country: client_country,
+        });
+    let options = RenderOptions {
+        width: art::resolve_width_or(params.width.as_deref(), quality.width()),
+        format: match (format, params.ansi) {
+            // still plain text, only colored
+            (None | Some(ArtFormat::Text), Some(true)) => ArtFormat::Ansi,
+            (Some(format), _) => format,
+            (None, _) if json.is_some() => ArtFormat::Html,
+            (None, _) => params
+                .format
+                .as_deref()
+                .and_then(ArtFormat::from_name)
+                .unwrap_or_else(|| ArtFormat::negotiate(accept)),
+        },
+        color: params.color.unwrap_or(true),
+        style,
+        invert: params.invert.unwrap_or(false),
+        ratio: art::resolve_ratio(params.ratio.as_deref()),
+        threshold: params.bw_threshold,
+    };
+    span.set_attribute(KeyValue::new("art_quality", quality.name()));
+    span.set_attribute(KeyValue::new("art_width", options.width as i64));
+    span.set_attribute(KeyValue::new("art_format", options.format.name()));
+    span.set_attribute(KeyValue::new("art_color", options.color));
+    span.set_attribute(KeyValue::new("art_style", options.style.name()));
+    if let ArtStyle::Custom(charset) = options.style {
+        span.set_attribute(KeyValue::new("art_charset", charset.as_str().to_owned()));
+    }
+    span.set_attribute(KeyValue::new("art_invert", options.invert));
+    span.set_attribute(KeyValue::new("art_ratio", options.ratio as f64 / 100.0));
+    if let Some(threshold) = options.threshold {
+        span.set_attribute(KeyValue::new("art_bw_threshold", threshold as i64));
+    }
+    span.set_attribute(KeyValue::new("art_json", json.is_some()));
+
+    if head {
+        span.set_attribute(KeyValue::new("head", true));
+        let content_type = match json {
+            Some(_) => JSON_CONTENT_TYPE,
+            None => options.format.content_type(),
+        };
+        return head_response(content_type, &query);
+    }
+    let extension = match json {
+        Some(_) => "json",
+        None => options.format.extension(),
+    };
+
+    let if_none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_owned);
+
+    let cx = Context::current_with_span(span);
+    let res = root_get_inner(
+        state.clone(),
+        query,
+        src,
+        options,
+        json,
+        json_errors,
+        if_none_match,
+    )
+    .with_context(cx.clone());
+    let mut res = within_budget(&state, &cx, json_errors, res).await;
+    // fallback cats included, but not errors
+    if params.download == Some(true) && res.status() == StatusCode::OK {
+        let disposition = format!("attachment; filename=\"cat.{extension}\"");
+        res.headers_mut().insert(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_str(&disposition).expect("filenames are valid header values"),
+        );
+    }
+    res
+}
+
+/// What `HEAD` gets, once the parameters are known to be valid: the headers
+/// that don't depend on which cat, without fetching or drawing one. That's
+/// all monitoring checks want, and they'd cost as much as a cat otherwise.
+fn head_response(content_type: &'static str, query: &ImageQuery) -> Response<BoxBody> {
+    (
+        StatusCode::OK,
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::VARY, "accept"),
+            (header::CACHE_CONTROL, cache_control(query)),
+        ],
+    )
+        .into_response()
+}
+
+/// Gives up on `res` once it's taken `$REQUEST_BUDGET_SECS`, answering with
+/// a 504 instead. Dropping it cancels whatever upstream calls it was making,
+/// and the handler's span in `cx` is marked as failed.
+async fn within_budget(
+    state: &ServerState,
+    cx: &Context,
+    json_errors: bool,
+    res: impl Future<Output = Response<BoxBody>>,
+) -> Response<BoxBody> {
+    let Ok(res) = tokio::time::timeout(state.request_budget, res).await else {
+        let e = color_eyre::eyre::eyre!("gave up after {:?}", state.request_budget);
+        sampling::record_error(cx, format!("{e}"));
+        let code = ErrorCode::RequestTimeout;
+        state.recent_errors.record(code, &e, request_id::current());
+        warn!("Could not serve a cat ({}): {e}", code.as_str());
+        return ApiError {
+            code,
+            json: json_errors,
+        }
+        .into_response();
+    };
+    res
+}
+
+#[derive(serde::Deserialize)]
+struct GalleryParams {
+    count: Option<String>,
+    width: Option<String>,
+}
+
+/// Several cats on one page. Cats that fail to load are left out, the page
+/// only errors out if none made it.
+async fn gallery_get(
+    headers: HeaderMap,
+    Query(params): Query<GalleryParams>,
+    State(state): State<ServerState>,
+) -> Response<BoxBody> {
+    let tracer = global::tracer("");
+    let mut span = tracer.start_with_context("gallery_get", &state.trace_sampler.root_context());
+    if let Some(id) = request_id::current() {
+        span.set_attribute(KeyValue::new("request_id", id));
+    }
+
+    let count = gallery::resolve_count(params.count.as_deref());
+    let options = RenderOptions {
+        width: art::resolve_width(params.width.as_deref()),
+        ..RenderOptions::default()
+    };
+    let json_errors = error::wants_json(headers.get(header::ACCEPT).and_then(|h| h.to_str().ok()));
+    span.set_attribute(KeyValue::new("count", count as i64));
+    span.set_attribute(KeyValue::new("art_width", options.width as i64));
+    let cx = Context::current_with_span(span);
+
+    // spawned tasks don't inherit the request ID, so each gets it passed
+    let id = request_id::current();
+    let mut tasks = tokio::task::JoinSet::new();
+    for _ in 0..count {
+        let state = state.clone();
+        let task_cx =
+            Context::current_with_span(tracer.start_with_context("get_cat_ascii_art", &cx));
+        tasks.spawn(request_id::scope(
+            id.clone(),
+            async move { get_cat_ascii_art(&state, &ImageQuery::default(), options).await }
+                .with_context(task_cx),
+        ));
+    }
+
+    // the status goes out with the first bytes, so one cat has to make it
+    // first: if none do, that's still an error page
+    let mut last_error = None;
+    let first = loop {
+        match next_gallery_art(&mut tasks).await {
+            Some(Ok(art)) => break art,
+            Some(Err(code)) => last_error = Some(code),
+            None => {
+                cx.span().set_attribute(KeyValue::new("images_served", 0));
+                let code = last_error.unwrap_or(ErrorCode::Internal);
+                sampling::record_error(&cx, format!("no cats served ({})", code.as_str()));
+                return ApiError {
+                    code,
+                    json: json_errors,
+                }
+                .into_response();
+            }
+        }
+    };
+
+    // then every other cat is sent as soon as it's drawn. If the client goes
+    // away, the remaining tasks are dropped, which cancels them.
+    let (mut tx, body) = axum::body::Body::channel();
+    tokio::spawn(request_id::scope(id, async move {
+        let mut served = 1;
+        if tx
+            .send_data([gallery::HEADER, &gallery::entry(&first)].concat().into())
+            .await
+            .is_err()
+        {
+            return;
+        }
+        while let Some(res) = next_gallery_art(&mut tasks).await {
+            let Ok(art) = res else { continue };
+            served += 1;
+            if tx.send_data(gallery::entry(&art).into()).await.is_err() {
+                return;
+            }
+        }
+        _ = tx.send_data(gallery::FOOTER.into()).await;
+        cx.span()
+            .set_attribute(KeyValue::new("images_served", served as i64));
+    }));
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, ArtFormat::Html.content_type())],
+        axum::body::boxed(body),
+    )
+        .into_response()
+}
+
+/// Waits for the next gallery cat, `None` once they're all done. Failures
+/// are logged, a gallery goes on without them.
+async fn next_gallery_art(
+    tasks: &mut tokio::task::JoinSet<color_eyre::Result<Art>>,
+) -> Option<Result<String, ErrorCode>> {
+    Some(match tasks.join_next().await? {
+        Ok(Ok(art)) => Ok(art.body),
+        Ok(Err(e)) => {
+            let code = ErrorCode::classify(&e);
+            warn!(
+                "Leaving a cat out of the gallery ({}): {e:?}",
+                code.as_str()
+            );
+            Err(code)
+        }
+        Err(e) => {
+            warn!("Leaving a cat out of the gallery: {e}");
+            Err(ErrorCode::Internal)
+        }
+    })
+}
+
+#[derive(serde::Deserialize)]
+struct FancyParams {
+    width: Option<String>,
+}
+
+/// A cat with a fact under it, both fetched at once. Only the cat is
+/// needed: without a fact, there's [fact::PLACEHOLDER].
+async fn fancy_get(
+    headers: HeaderMap,
+    Query(params): Query<FancyParams>,
+    State(state): State<ServerState>,
+) -> Response<BoxBody> {
+    let tracer = global::tracer("");
+    let mut span = tracer.start_with_context("fancy_get", &state.trace_sampler.root_context());
+    if let Some(id) = request_id::current() {
+        span.set_attribute(KeyValue::new("request_id", id));
+    }
+
+    let options = RenderOptions {
+        width: art::resolve_width(params.width.as_deref()),
+        ..RenderOptions::default()
+    };
+    let json_errors = error::wants_json(headers.get(header::ACCEPT).and_then(|h| h.to_str().ok()));
+    span.set_attribute(KeyValue::new("art_width", options.width as i64));
+    let cx = Context::current_with_span(span);
+
+    let query = ImageQuery::default();
+    let (art, fact) = tokio::join!(
+        get_cat_ascii_art(&state, &query, options)
+            .with_context(cx.with_span(tracer.start_with_context("get_cat_ascii_art", &cx))),
+        fact::fetch(&state.client, &state.cat_fact_url)
+            .with_context(cx.with_span(tracer.start_with_context("fetch_cat_fact", &cx))),
+    );
+
+    let art = match art {
+        Ok(art) => art,
+        Err(e) => {
+            sampling::record_error(&cx, format!("{e}"));
+            let code = ErrorCode::classify(&e);
+            state.recent_errors.record(code, &e, request_id::current());
+            warn!("Could not get a fancy cat ({}): {e:?}", code.as_str());
+            return ApiError {
+                code,
+                json: json_errors,
+            }
+            .into_response();
+        }
+    };
+    let fact = fact.unwrap_or_else(|e| {
+        warn!("Could not get a cat fact, using the placeholder: {e:?}");
+        fact::PLACEHOLDER.to_owned()
+    });
+    cx.span()
+        .set_attribute(KeyValue::new("fact_placeholder", fact == fact::PLACEHOLDER));
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, ArtFormat::Html.content_type())],
+        fact::page(&art.body, &fact),
+    )
+        .into_response()
+}
+
+/// Served when the image source or image host is down, so there's always a
+/// cat on the page
+const FALLBACK_CAT: &str = r#"
+      /\_____/\
+     /  o   o  \
+    ( ==  ^  == )
+     )         (
+    (           )
+   ( (  )   (  ) )
+  (__(__)___(__)__)
+
+The cat picture service is napping,
+here's a cat we drew earlier.
+"#;
+
+/// Serves the cat picture rather than its art
+async fn image_get_inner(
+    state: ServerState,
+    query: ImageQuery,
+    encoding: Encoding,
+    json_errors: bool,
+) -> Response<BoxBody> {
+    let res = get_cat_image(&state, &query, encoding)
+        .with_context(Context::current_with_span(
+            global::tracer("").start("get_cat_image"),
+        ))
+        .await;
+    match res {
+        Ok(bytes) => {
+            get_active_span(|span| {
+                span.set_attribute(KeyValue::new("response_bytes", bytes.len() as i64))
+            });
+            (
+                StatusCode::OK,
+                [
+                    (header::CONTENT_TYPE, encoding.content_type()),
+                    (header::CACHE_CONTROL, cache_control(&query)),
+                ],
+                bytes,
+            )
+                .into_response()
+        }
+        Err(e) => {
+            sampling::record_error(&Context::current(), format!("{e}"));
+            let code = ErrorCode::classify(&e);
+            state.recent_errors.record(code, &e, request_id::current());
+            warn!("Could not serve a cat picture ({}): {e:?}", code.as_str());
+            ApiError {
+                code,
+                json: json_errors,
+            }
+            .into_response()
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct UrlParams {
+    breed: Option<String>,
+    animal: Option<String>,
+    seed: Option<u64>,
+}
+
+#[derive(serde::Serialize)]
+struct ImageUrl {
+    url: String,
+}
+
+/// Where the picture `/` would have drawn is, for clients that would rather
+/// show it themselves. Nothing gets downloaded.
+async fn url_get(
+    Query(params): Query<UrlParams>,
+    State(state): State<ServerState>,
+) -> Response<BoxBody> {
+    let tracer = global::tracer("");
+    let mut span = tracer.start_with_context("url_get", &state.trace_sampler.root_context());
+    if let Some(id) = request_id::current() {
+        span.set_attribute(KeyValue::new("request_id", id));
+    }
+    let error = |code| ApiError { code, json: true }.into_response();
+
+    let Some(animal) = parse_choice(params.animal.as_deref(), Animal::from_name) else {
+        return error(ErrorCode::UnknownAnimal);
+    };
+    let query = ImageQuery {
+        animal,
+        breed: params
+            .breed
+            .map(|breed| breed.trim().to_owned())
+            .filter(|breed| !breed.is_empty()),
+        seed: params.seed,
+    };
+    span.set_attribute(KeyValue::new("animal", animal.name()));
+
+    let cx = Context::current_with_span(span);
+    match get_image_urls(&state, &query)
+        .with_context(cx.clone())
+        .await
+    {
+        Ok((mut urls, _)) => (
+            [(header::CACHE_CONTROL, cache_control(&query))],
+            Json(ImageUrl {
+                url: urls.swap_remove(0),
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            sampling::record_error(&cx, format!("{e}"));
+            let code = ErrorCode::classify(&e);
+            state.recent_errors.record(code, &e, request_id::current());
+            warn!("Could not get a cat URL ({}): {e:?}", code.as_str());
+            error(code)
+        }
+    }
+}
+
+/// How long caches may keep a cat. A random cat is only good once, but a
+/// seed or breed pins the result down (or near enough), so it's worth
+/// keeping around for a bit.
+fn cache_control(query: &ImageQuery) -> &'static str {
+    if query.seed.is_some() || query.breed.is_some() {
+        "public, max-age=60"
+    } else {
+        "no-store"
+    }
+}
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// What `?format=json` wraps the art with, besides the image
+struct JsonArt {
+    /// Where the request came from, if that's known
+    country: Option<String>,
+}
+
+/// The body `?format=json` gets
+#[derive(serde::Serialize)]
+struct ArtJson<'a> {
+    art: &'a str,
+    image_url: &'a str,
+    width: u32,
+    height: u32,
+    country: Option<&'a str>,
+}
+
+//               to here 👇
+async fn root_get_inner(
+    state: ServerState,
+    query: ImageQuery,
+    src: Option<reqwest::Url>,
+    options: RenderOptions,
+    json: Option<JsonArt>,
+    json_errors: bool,
+    if_none_match: Option<String>,
+) -> Response<BoxBody> {
+    let tracer = global::tracer("");
+
+    let timer = state.metrics.art_duration.start_timer();
+    let res = match &src {
+        Some(src) => {
+            get_custom_ascii_art(&state, src, options)
+                .with_context(Context::current_with_span(
+                    tracer.start("get_custom_ascii_art"),
+                ))
+                .await
+        }
+        //              passing the state 👇
+        None => {
+            get_cat_ascii_art(&state, &query, options)
+                .with_context(Context::current_with_span(
+                    tracer.start("get_cat_ascii_art"),
+                ))
+                .await
+        }
+    };
+    timer.observe_duration();
+    let cache_control = cache_control(&query);
+
+    match res {
+        Ok(Art {
+            body,
+            etag,
+            image_size: (image_width, image_height),
+            image_url,
+        }) => {
+            if let Some(if_none_match) = &if_none_match {
+                if cache::etag_matches(if_none_match, &etag) {
+                    get_active_span(|span| span.set_attribute(KeyValue::new("not_modified", true)));
+                    return (
+                        StatusCode::NOT_MODIFIED,
+                        [
+                            (header::ETAG, etag.as_str()),
+                            (header::VARY, "accept"),
+                            (header::CACHE_CONTROL, cache_control),
+                        ],
+                    )
+                        .into_response();
+                }
+            }
+            let (content_type, body) = match &json {
+                Some(JsonArt { country }) => {
+                    let json = ArtJson {
+                        art: &body,
+                        image_url: &image_url,
+                        width: image_width,
+                        height: image_height,
+                        country: country.as_deref(),
+                    };
+                    let json = serde_json::to_string(&json).expect("art serializes to JSON");
+                    (JSON_CONTENT_TYPE, json)
+                }
+                None => (options.format.content_type(), body),
+            };
+            // recorded on the handler's span, next to `art_format`
+            get_active_span(|span| {
+                span.set_attribute(KeyValue::new("response_bytes", body.len() as i64))
+            });
+            (
+                StatusCode::OK,
+                [
+                    (header::CONTENT_TYPE, content_type),
+                    (header::VARY, "accept"),
+                    (header::ETAG, etag.as_str()),
+                    (header::CACHE_CONTROL, cache_control),
+                ],
+                [
+                    ("x-image-width", image_width.to_string()),
+                    ("x-image-height", image_height.to_string()),
+                ],
+                body,
+            )
+                .into_response()
+        }
+        Err(e) => {
+            sampling::record_error(&Context::current(), format!("{e}"));
+            let code = ErrorCode::classify(&e);
+            state.recent_errors.record(code, &e, request_id::current());
+            // retries are exhausted by now, a drawing beats an error page. Not
+            // for `?src=` though, that wasn't asking for a cat, nor for JSON,
+            // which has no image to point at.
+            if state.fallback_cat && code.is_upstream() && src.is_none() && json.is_none() {
+                warn!(
+                    "Could not fetch a cat ({}), serving the fallback: {e:?}",
+                    code.as_str()
+                );
+                get_active_span(|span| span.set_attribute(KeyValue::new("fallback", true)));
+                return (
+                    StatusCode::OK,
+                    [
+                        (header::CONTENT_TYPE, options.format.content_type()),
+                        (header::VARY, "accept"),
+                        (HeaderName::from_static("x-fallback"), "true"),
+                        // so the real cat shows up as soon as it can
+                        (header::CACHE_CONTROL, "no-store"),
+                    ],
+                    art::from_plain(FALLBACK_CAT, options.format),
+                )
+                    .into_response();
+            }
+            warn!("Could not serve a cat ({}): {e:?}", code.as_str());
+            ApiError {
+                code,
+                json: json_errors,
+            }
+            .into_response()
+        }
+    }
+}
+
+//                   to here 👇
+async fn get_cat_ascii_art(
+    state: &ServerState,
+    query: &ImageQuery,
+    options: RenderOptions,
+) -> color_eyre::Result<Art> {
+    //   and then our helper functions 👇
+    let (image_urls, allow_files) = get_image_urls(state, query).await?;
+    draw_first(state, &state.client, image_urls, allow_files, options).await
+}
+
+/// The picture at `?src=`, fetched with a client that only connects to the
+/// public addresses it was checked against
+async fn get_custom_ascii_art(
+    state: &ServerState,
+    src: &reqwest::Url,
+    options: RenderOptions,
+) -> color_eyre::Result<Art> {
+    let client = custom_src::pinned_client(src).await?;
+    draw_first(state, &client, vec![src.to_string()], false, options).await
+}
+
+/// Draws the first of `image_urls` that loads, unless one was drawn already
+async fn draw_first(
+    state: &ServerState,
+    client: &reqwest::Client,
+    mut image_urls: Vec<String>,
+    allow_files: bool,
+    options: RenderOptions,
+) -> color_eyre::Result<Art> {
+    // any candidate will do, so a cached one saves a download
+    let cached = image_urls.iter().find_map(|url| {
+        state.art_cache.get(&ArtKey {
+            url: url.clone(),
+            options,
+        })
+    });
+    get_active_span(|span| span.set_attribute(KeyValue::new("cache_hit", cached.is_some())));
+    if let Some(art) = cached {
+        return Ok(art);
+    }
+
+    let _permit = state.conversions.acquire().await?;
+    let (index, image) = load_first_image(state, client, &image_urls, allow_files).await?;
+    let key = ArtKey {
+        url: image_urls.swap_remove(index),
+        options,
+    };
+
+    // the original's, even if it's drawn from a smaller copy
+    let image_size = (image.width(), image.height());
+    let max_dimension = state.download_limits.max_dimension;
+    let ascii_art = spawn_blocking(move || {
+        let tracer = global::tracer("");
+        let image = tracer.in_span("image::resize", |cx| {
+            let span = cx.span();
+            span.set_attribute(KeyValue::new("original_width", image.width() as i64));
+            span.set_attribute(KeyValue::new("original_height", image.height() as i64));
+            let image = art::fit(image, max_dimension);
+            span.set_attribute(KeyValue::new("scaled_width", image.width() as i64));
+            span.set_attribute(KeyValue::new("scaled_height", image.height() as i64));
+            image
+        });
+        tracer.in_span("artem::convert", |_cx| options.render(image))
+    })
+    .await;
+    let art = Art::new(options, ascii_art, image_size, key.url.clone());
+
+    state.art_cache.insert(key, art.clone());
+    Ok(art)
+}
+
+/// The cat picture itself, re-encoded as `encoding`
+async fn get_cat_image(
+    state: &ServerState,
+    query: &ImageQuery,
+    encoding: Encoding,
+) -> color_eyre::Result<Vec<u8>> {
+    let (image_urls, allow_files) = get_image_urls(state, query).await?;
+    let _permit = state.conversions.acquire().await?;
+    let (_, image) = load_first_image(state, &state.client, &image_urls, allow_files).await?;
+    spawn_blocking(move || {
+        global::tracer("").in_span("image::encode", |cx| {
+            cx.span()
+                .set_attribute(KeyValue::new("encoding", encoding.name()));
+            encoding
+                .encode(&image)
+                .wrap_err_with(|| format!("Could not encode the image as {}", encoding.name()))
+        })
+    })
+    .await
+}
+
+/// Asks the image source for candidate URLs, unless its circuit breaker is
+/// open. Also returns whether those may be local files.
+async fn get_image_urls(
+    state: &ServerState,
+    query: &ImageQuery,
+) -> color_eyre::Result<(Vec<String>, bool)> {
+    let (source, breaker) = state.source_for(query.animal);
+    let allowed = breaker.allow();
+    get_active_span(|span| span.set_attribute(KeyValue::new("circuit", breaker.state_name())));
+    allowed.wrap_err("Not asking the image source for now")?;
+    let res = source
+        .fetch_image_urls(&state.client, query)
+        .with_context(Context::current_with_span(
+            global::tracer("").start("fetch_image_urls"),
+        ))
+        .await;
+    // an unknown breed is no reason to stop asking
+    breaker.record(match &res {
+        Ok(_) => true,
+        Err(e) => !ErrorCode::classify(e).is_upstream(),
+    });
+    Ok((res?, source.serves_local_files()))
+}
+
+/// Downloads and decodes the first of `urls` that works, in case an image
+/// host is down, recording which one on the current span. Fails like the
+/// last one did if none work.
+async fn load_first_image(
+    state: &ServerState,
+    client: &reqwest::Client,
+    urls: &[String],
+    allow_files: bool,
+) -> color_eyre::Result<(usize, image::DynamicImage)> {
+    let mut last_error = None;
+    for (index, url) in urls.iter().enumerate() {
+        match load_image(state, client, url, allow_files).await {
+            Ok(image) => {
+                get_active_span(|span| {
+                    span.set_attribute(KeyValue::new("candidate_index", index as i64))
+                });
+                return Ok((index, image));
+            }
+            Err(e) => {
+                warn!("Could not load candidate image {index} ({url}): {e}");
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| color_eyre::eyre::Report::new(source::NoImageFound)))
+}
+
+/// Downloads and decodes an image
+async fn load_image(
+    state: &ServerState,
+    client: &reqwest::Client,
+    url: &str,
+    allow_files: bool,
+) -> color_eyre::Result<image::DynamicImage> {
+    let tracer = global::tracer("");
+    let image_bytes = download::download_file(client, url, allow_files, &state.download_limits)
+        .with_context(Context::current_with_span(tracer.start("download_file")))
+        .await?;
+
+    spawn_blocking(move || {
+        global::tracer("").in_span("image::load_from_memory", |cx| {
+            if let Ok(format) = image::guess_format(&image_bytes) {
+                cx.span()
+                    .set_attribute(KeyValue::new("format", format!("{format:?}")));
+            }
+            let img = image::load_from_memory(&image_bytes).map_err(|e| {
+                cx.span().set_status(Status::Error {
+                    description: format!("{e}").into(),
+                });
+                color_eyre::eyre::Report::new(e).wrap_err("The image host served an invalid image")
+            })?;
+            cx.span()
+                .set_attribute(KeyValue::new("width", img.width() as i64));
+            cx.span()
+                .set_attribute(KeyValue::new("height", img.height() as i64));
+            Ok(img)
+        })
+    })
+    .await
+}
+
+/// Runs `f` on tokio's blocking thread pool. Decoding and converting images
+/// is CPU-bound and can take a while for big pictures: done inline, it holds
+/// up every other task on the same worker thread, health checks included.
+///
+/// The current context goes along, so spans started in `f` still nest under
+/// the request's span.
+async fn spawn_blocking<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let cx = Context::current();
+    let res = tokio::task::spawn_blocking(move || {
+        let _guard = cx.attach();
+        f()
+    })
+    .await;
+    match res {
+        Ok(value) => value,
+        // keep panicking as if `f` had run inline
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use locat::Locat;
+
+    use super::*;
+
+    #[test]
+    fn test_db() {
+        let country_db_env_var = "GEOLITE2_COUNTRY_DB";
+        let country_db_path = std::env::var(country_db_env_var)
+            .unwrap_or_else(|_| panic!("${country_db_env_var} must be set"));
+
+        let analytics_db_env_var = "ANALYTICS_DB";
+        let analytics_db_path = std::env::var(analytics_db_env_var)
+            .unwrap_or_else(|_| panic!("${analytics_db_env_var} must be set"));
+        let _locat = Locat::new(&country_db_path, &analytics_db_path).unwrap();
+
+        println!("{country_db_path:?}");
+        println!("{analytics_db_path:?}");
+    }
+
+    /// Always hands out the same image from disk, so no network is involved
+    struct FixedSource {
+        url: String,
+    }
+
+    #[async_trait::async_trait]
+    impl ImageSource for FixedSource {
+        async fn fetch_image_url(
+            &self,
+            _client: &reqwest::Client,
+            _query: &ImageQuery,
+        ) -> color_eyre::Result<String> {
+            Ok(self.url.clone())
+        }
+
+        fn serves_local_files(&self) -> bool {
+            true
+        }
+    }
+
+    /// Hands out several images at once, in order
+    struct CandidateSource {
+        urls: Vec<String>,
+    }
+
+    #[async_trait::async_trait]
+    impl ImageSource for CandidateSource {
+        async fn fetch_image_url(
+            &self,
+            _client: &reqwest::Client,
+            _query: &ImageQuery,
+        ) -> color_eyre::Result<String> {
+            Ok(self.urls[0].clone())
+        }
+
+        async fn fetch_image_urls(
+            &self,
+            _client: &reqwest::Client,
+            _query: &ImageQuery,
+        ) -> color_eyre::Result<Vec<String>> {
+            Ok(self.urls.clone())
+        }
+
+        fn serves_local_files(&self) -> bool {
+            true
+        }
+    }
+
+    /// Takes its time, to keep requests in flight
+    struct SlowSource {
+        url: String,
+        delay: Duration,
+        called: Arc<tokio::sync::Notify>,
+    }
+
+    #[async_trait::async_trait]
+    impl ImageSource for SlowSource {
+        async fn fetch_image_url(
+            &self,
+            _client: &reqwest::Client,
+            _query: &ImageQuery,
+        ) -> color_eyre::Result<String> {
+            self.called.notify_one();
+            tokio::time::sleep(self.delay).await;
+            Ok(self.url.clone())
+        }
+
+        fn serves_local_files(&self) -> bool {
+            true
+        }
+    }
+
+    fn test_state(image_source: Arc<dyn ImageSource>) -> ServerState {
+        // like `serve` does
+        colored::control::set_override(true);
+        ServerState {
+            client: reqwest::Client::new(),
+            locat: None,
+            visits: None,
+            image_source: image_source.clone(),
+            dog_source: image_source,
+            // nothing listens there
+            cat_fact_url: "http://127.0.0.1:1/fact".into(),
+            cat_breaker: Arc::new(CircuitBreaker::new(0, Duration::ZERO)),
+            dog_breaker: Arc::new(CircuitBreaker::new(0, Duration::ZERO)),
+            art_cache: Arc::new(ArtCache::new(0, Duration::ZERO)),
+            metrics: Arc::new(Metrics::new()),
+            rate_limiter: Arc::new(RateLimiter::new(0, false)),
+            download_limits: Arc::new(DownloadLimits::default()),
+            request_limits: Arc::new(RequestLimits::default()),
+            conversions: Arc::new(ConversionLimiter::new(64, Duration::from_secs(5))),
+            recent_errors: Default::default(),
+            settings: Default::default(),
+            fallback_cat: true,
+            custom_src: true,
+            blocked_countries: Arc::new([]),
+            trust_fly_client_ip: false,
+            admin_token: Arc::new(AdminToken::new(None)),
+            maintenance: Arc::new(Maintenance::new(Duration::from_secs(60))),
+            started_at: Instant::now(),
+            trace_sampler: TraceSampler::new(1.0),
+            request_budget: Duration::from_secs(10),
+            access_log_excluded: Arc::new([]),
+        }
+    }
+
+    /// Writes a small gradient to a temporary PNG, returning its URL
+    fn test_image(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("catscii-{}-{name}.png", std::process::id()));
+        image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, 128])
+        })
+        .save(&path)
+        .unwrap();
+        reqwest::Url::from_file_path(&path).unwrap().into()
+    }
+
+    /// A router with the [test_state] defaults, always serving
+    /// `test_image(name)`
+    fn test_app(name: &str) -> Router {
+        let source = FixedSource {
+            url: test_image(name),
+        };
+        build_router(test_state(Arc::new(source)), None)
+    }
+
+    async fn get(
+        app: Router,
+        uri: &str,
+        request_headers: &[(&str, &str)],
+    ) -> (StatusCode, HeaderMap, String) {
+        request(app, Method::GET, uri, request_headers).await
+    }
+
+    async fn request(
+        app: Router,
+        method: Method,
+        uri: &str,
+        request_headers: &[(&str, &str)],
+    ) -> (StatusCode, HeaderMap, String) {
+        use tower::ServiceExt;
+
+        let mut req = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(axum::body::Body::empty())
+            .unwrap();
+        *req.headers_mut() = headers(request_headers);
+        let res = app.oneshot(req).await.unwrap();
+        let (parts, body) = res.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        (
+            parts.status,
+            parts.headers,
+            String::from_utf8_lossy(&body).into_owned(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_root() {
+        let app = test_app("root");
+
+        let (status, headers, body) = get(app, "/", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            headers[header::CONTENT_TYPE],
+            ArtFormat::Html.content_type()
+        );
+        assert!(body.contains("<!DOCTYPE html>"), "{body}");
+        assert!(body.contains("<span style="), "{body}");
+        assert_eq!(headers[header::CACHE_CONTROL], "no-store");
+        assert_eq!(headers["x-image-width"], "64");
+        assert_eq!(headers["x-image-height"], "64");
+    }
+
+    #[tokio::test]
+    async fn test_cat_txt() {
+        let app = test_app("cat_txt");
+
+        let (status, headers, body) = get(app, "/cat.txt", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            headers[header::CONTENT_TYPE],
+            ArtFormat::Text.content_type()
+        );
+        assert!(!body.contains('<'), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_style() {
+        let app = test_app("style");
+
+        let (status, _, body) = get(app.clone(), "/cat.txt?style=minimal", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.chars().all(|c| "#+. \n".contains(c)), "{body}");
+        let (status, _, _) = get(app, "/?style=fancy", &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_animal() {
+        let app = test_app("animal");
+
+        let (status, _, _) = get(app.clone(), "/?animal=dog", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, body) = get(app, "/?animal=ferret", &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.starts_with("Unknown animal"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_ansi() {
+        let app = test_app("ansi");
+
+        for uri in ["/?ansi=true", "/cat.txt?ansi=true"] {
+            let (status, headers, body) = get(app.clone(), uri, &[]).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(
+                headers[header::CONTENT_TYPE],
+                ArtFormat::Ansi.content_type()
+            );
+            assert!(body.starts_with("\x1b["), "{uri}: {body:?}");
+        }
+        let (_, _, body) = get(app, "/", &[("accept", art::ANSI_MEDIA_TYPE)]).await;
+        assert!(body.starts_with("\x1b["), "{body:?}");
+    }
+
+    #[tokio::test]
+    async fn test_json() {
+        let app = test_app("json");
+
+        let (status, headers, body) = get(app, "/?format=json", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert!(
+            json["art"].as_str().unwrap().contains("<span style="),
+            "{body}"
+        );
+        assert_eq!(json["image_url"], test_image("json"));
+        assert_eq!(json["width"], 64);
+        assert_eq!(json["height"], 64);
+        // no geolocation in tests
+        assert!(json["country"].is_null(), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_invert() {
+        let app = test_app("invert");
+
+        let (_, _, plain) = get(app.clone(), "/cat.txt", &[]).await;
+        let (status, _, inverted) = get(app.clone(), "/cat.txt?invert=true", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_ne!(plain, inverted);
+        let (status, _, _) = get(app, "/?invert=maybe", &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_bw_threshold() {
+        let app = test_app("bw_threshold");
+
+        let (_, _, plain) = get(app.clone(), "/cat.txt", &[]).await;
+        let (status, _, stark) = get(app.clone(), "/cat.txt?bw_threshold=128", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_ne!(plain, stark);
+        let (status, _, _) = get(app, "/?bw_threshold=256", &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_charset() {
+        let app = test_app("charset");
+
+        let (status, _, body) = get(app.clone(), "/cat.txt?charset=Xx%20", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.chars().all(|c| "Xx \n".contains(c)), "{body}");
+        for charset in ["", "%3Cscript%3E", &"x".repeat(33)] {
+            let uri = format!("/?charset={charset}");
+            let (status, _, _) = get(app.clone(), &uri, &[]).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{charset}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quality() {
+        let app = test_app("quality");
+
+        let (status, _, low) = get(app.clone(), "/cat.txt?quality=low", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(low.lines().next().unwrap().chars().count(), 40, "{low}");
+        assert!(low.chars().all(|c| "#+. \n".contains(c)), "{low}");
+        let (_, _, narrow) = get(app.clone(), "/cat.txt?quality=low&width=30", &[]).await;
+        assert_eq!(
+            narrow.lines().next().unwrap().chars().count(),
+            30,
+            "{narrow}"
+        );
+        let (status, _, body) = get(app, "/?quality=ultra", &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.starts_with("Unknown quality"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let app = test_app("download");
+
+        let (_, headers, _) = get(app.clone(), "/", &[]).await;
+        assert!(!headers.contains_key(header::CONTENT_DISPOSITION));
+        let (status, headers, _) = get(app.clone(), "/?download=true", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            headers[header::CONTENT_DISPOSITION],
+            r#"attachment; filename="cat.html""#
+        );
+        let (_, headers, _) = get(app.clone(), "/cat.txt?download=true", &[]).await;
+        assert_eq!(
+            headers[header::CONTENT_DISPOSITION],
+            r#"attachment; filename="cat.txt""#
+        );
+        let (_, headers, _) = get(app.clone(), "/?format=json&download=true", &[]).await;
+        assert_eq!(
+            headers[header::CONTENT_DISPOSITION],
+            r#"attachment; filename="cat.json""#
+        );
+        let (status, _, _) = get(app, "/?download=please", &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_transcode() {
+        let app = test_app("transcode");
+
+        let (status, headers, _) = get(app.clone(), "/?as=webp", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/webp");
+        let (status, _, _) = get(app, "/?as=avif", &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_seed() {
+        let app = test_app("seed");
+
+        let (status, headers, _) = get(app.clone(), "/?seed=3", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CACHE_CONTROL], "public, max-age=60");
+        let (_, headers, _) = get(app.clone(), "/?breed=beng", &[]).await;
+        assert_eq!(headers[header::CACHE_CONTROL], "public, max-age=60");
+        let (status, _, _) = get(app, "/?seed=lucky", &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_head() {
+        // nothing listens on port 1: only a request that never fetches a
+        // cat succeeds
+        let source = FixedSource {
+            url: "http://127.0.0.1:1/cat.png".into(),
+        };
+        let mut state = test_state(Arc::new(source));
+        state.fallback_cat = false;
+        let app = build_router(state, None);
+
+        let (status, headers, body) = request(app.clone(), Method::HEAD, "/", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            headers[header::CONTENT_TYPE],
+            ArtFormat::Html.content_type()
+        );
+        assert_eq!(headers[header::CACHE_CONTROL], "no-store");
+        assert!(body.is_empty(), "{body}");
+
+        let (status, headers, body) =
+            request(app.clone(), Method::HEAD, "/cat.txt?seed=3", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            headers[header::CONTENT_TYPE],
+            ArtFormat::Text.content_type()
+        );
+        assert_eq!(headers[header::CACHE_CONTROL], "public, max-age=60");
+        assert!(body.is_empty(), "{body}");
+
+        let (_, headers, _) = request(app.clone(), Method::HEAD, "/?as=png", &[]).await;
+        assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+        let (_, headers, _) = request(app.clone(), Method::HEAD, "/?format=json", &[]).await;
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+
+        // still validated like GET
+        let (status, _, body) = request(app.clone(), Method::HEAD, "/?style=fancy", &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.is_empty(), "{body}");
+
+        let (status, _, _) = get(app, "/", &[]).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let app = test_app("compression");
+
+        let req = axum::http::Request::get("/")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let res = tower::ServiceExt::oneshot(app, req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            res.headers()[header::CONTENT_TYPE],
+            ArtFormat::Html.content_type()
+        );
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        // gzip magic
+        assert_eq!(body[..2], [0x1f, 0x8b]);
+    }
+
+    #[tokio::test]
+    async fn test_etag() {
+        let app = test_app("etag");
+
+        let (status, headers, _) = get(app.clone(), "/cat.txt", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        let etag = headers[header::ETAG].to_str().unwrap().to_owned();
+        let etag = etag.as_str();
+
+        let (status, headers, body) =
+            get(app.clone(), "/cat.txt", &[("if-none-match", etag)]).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert_eq!(headers[header::ETAG], etag);
+        assert!(body.is_empty());
+
+        // same image, different art
+        let (status, headers, _) = get(app, "/cat.txt?width=40", &[("if-none-match", etag)]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_ne!(headers[header::ETAG], etag);
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let app = test_app("status");
+
+        get(app.clone(), "/cat.txt", &[]).await;
+        get(app.clone(), "/status", &[]).await;
+        let (status, _, body) = get(app, "/status", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        // only the cat counts
+        assert!(
+            body.contains(r#""requests_served":1,"geolocation":false"#),
+            "{body}"
+        );
+        // test_state has no cache
+        assert!(
+            body.contains(r#""art_cache":{"size":0,"capacity":0,"hits":0,"misses":0}"#),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_healthz_deep() {
+        let app = test_app("deep");
+        let (status, _, body) = get(app, "/healthz/deep", &[]).await;
+        // no databases in tests, but the image source is there
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with(r#"{"status":"degraded""#), "{body}");
+        assert!(body.contains(r#""image_source":{"status":"ok"}"#), "{body}");
+
+        let source = SlowSource {
+            url: test_image("deep"),
+            delay: Duration::from_secs(60),
+            called: Default::default(),
+        };
+        let app = build_router(test_state(Arc::new(source)), None);
+        let start = Instant::now();
+        let (status, _, body) = get(app, "/healthz/deep", &[]).await;
+        assert!(start.elapsed() < DEEP_CHECK_TIMEOUT * 2);
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("timed out"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_method_not_allowed() {
+        let app = test_app("method-not-allowed");
+
+        let (status, _, _) = get(app.clone(), "/", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, headers, body) = request(app.clone(), Method::POST, "/", &[]).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(headers[header::ALLOW], "GET,HEAD");
+        assert!(body.starts_with("Wrong method"), "{body}");
+
+        let (status, headers, body) = request(
+            app,
+            Method::GET,
+            "/admin/maintenance",
+            &[("accept", "application/json")],
+        )
+        .await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(headers[header::ALLOW], "POST");
+        assert!(
+            body.starts_with(r#"{"error":"method_not_allowed""#),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_openapi() {
+        let app = test_app("openapi");
+
+        let (status, headers, body) = get(app.clone(), "/openapi.json", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        let openapi: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        // every documented route exists, with that method
+        let paths = openapi["paths"].as_object().unwrap();
+        assert!(paths.len() > 10);
+        for (path, methods) in paths {
+            let uri = path.replace("{ip}", "1.2.3.4");
+            for method in methods.as_object().unwrap().keys() {
+                let method = Method::from_bytes(method.to_uppercase().as_bytes()).unwrap();
+                let (status, _, _) = request(app.clone(), method.clone(), &uri, &[]).await;
+                assert_ne!(status, StatusCode::NOT_FOUND, "{method} {path}");
+                assert_ne!(status, StatusCode::METHOD_NOT_ALLOWED, "{method} {path}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_not_found() {
+        let app = test_app("not-found");
+
+        let (status, headers, body) = get(app.clone(), "/no/cats/here", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            headers[header::CONTENT_TYPE],
+            ArtFormat::Html.content_type()
+        );
+        assert!(body.contains("( o.o )"), "{body}");
+        assert!(body.contains("&gt; ^ &lt;"), "{body}");
+
+        let (status, _, body) = get(app, "/nope", &[("accept", "application/json")]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.starts_with(r#"{"error":"not_found""#), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_static() {
+        let app = test_app("static");
+
+        let (status, headers, _) = get(app.clone(), "/favicon.ico", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/x-icon");
+        assert!(headers.contains_key(header::CACHE_CONTROL));
+
+        let (status, _, body) = get(app.clone(), "/static/robots.txt", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with("User-agent"), "{body}");
+
+        let (status, _, _) = get(app, "/static/nope.css", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let called = Arc::new(tokio::sync::Notify::new());
+        let source = SlowSource {
+            url: test_image("shutdown"),
+            delay: Duration::from_millis(500),
+            called: called.clone(),
+        };
+        let app = build_router(test_state(Arc::new(source)), None);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(run_server(
+            listener,
+            app,
+            async {
+                _ = shutdown_rx.await;
+            },
+            Duration::from_secs(5),
+        ));
+
+        let in_flight = tokio::spawn(async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET /cat.txt HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        });
+        called.notified().await;
+        shutdown_tx.send(()).unwrap();
+
+        // the listener goes away as soon as hyper sees the signal
+        let mut refused = false;
+        for _ in 0..50 {
+            if tokio::net::TcpStream::connect(addr).await.is_err() {
+                refused = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(refused, "new connections should be refused");
+
+        let response = in_flight.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_limits() {
+        use tower::ServiceExt;
+
+        let app = test_app("limits");
+
+        let body = vec![b'x'; RequestLimits::default().max_body_bytes + 1];
+        let req = axum::http::Request::builder()
+            .method(Method::POST)
+            .uri("/geoip/1.1.1.1")
+            .header(header::CONTENT_LENGTH, body.len())
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let cookie = "x".repeat(RequestLimits::default().max_header_bytes);
+        let (status, _, _) = get(app, "/", &[("cookie", &cookie)]).await;
+        assert_eq!(status, StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let source = FixedSource {
+            url: test_image("cors"),
+        };
+        let cors = CorsLayer::new()
+            .allow_origin(AllowOrigin::exact("https://dash.example".parse().unwrap()))
+            .allow_methods([Method::GET]);
+        let app = build_router(test_state(Arc::new(source)), Some(cors));
+        let origin = ("origin", "https://dash.example");
+
+        let (status, headers, _) = request(
+            app.clone(),
+            Method::OPTIONS,
+            "/analytics.json",
+            &[origin, ("access-control-request-method", "GET")],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://dash.example"
+        );
+
+        // errors too, so dashboards can tell analytics are down
+        let (status, headers, _) = get(app.clone(), "/analytics.json", &[origin]).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://dash.example"
+        );
+
+        let (_, headers, _) = get(app, "/cat.txt", &[origin]).await;
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn test_fallback_cat() {
+        // nothing listens on port 1, so this fails fast without the network
+        let source = FixedSource {
+            url: "http://127.0.0.1:1/cat.png".into(),
+        };
+        let mut state = test_state(Arc::new(source));
+
+        let (status, headers, body) = get(build_router(state.clone(), None), "/cat.txt", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["x-fallback"], "true");
+        assert_eq!(body, FALLBACK_CAT);
+
+        state.fallback_cat = false;
+        let (status, headers, _) = get(build_router(state, None), "/cat.txt", &[]).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(!headers.contains_key("x-fallback"));
+    }
+
+    #[tokio::test]
+    async fn test_custom_src() {
+        let source = FixedSource {
+            url: test_image("custom_src"),
+        };
+        let mut state = test_state(Arc::new(source));
+        let app = build_router(state.clone(), None);
+
+        for src in [
+            "file:///etc/passwd",
+            "http://127.0.0.1:1/cat.png",
+            "http://%5B::1%5D/",
+        ] {
+            let uri = format!("/?src={src}");
+            let (status, _, body) = get(app.clone(), &uri, &[("accept", "application/json")]).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{src}");
+            assert!(
+                body.contains(r#""error":"forbidden_source""#),
+                "{src}: {body}"
+            );
+        }
+        // no cat in place of a custom picture
+        let (status, headers, _) = get(app.clone(), "/cat.txt?src=http://127.0.0.1:1/", &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(!headers.contains_key("x-fallback"));
+
+        state.custom_src = false;
+        let app = build_router(state, None);
+        let (status, _, _) = get(app.clone(), "/?src=https://example.com/cat.png", &[]).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _, _) = get(app, "/?src=", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_geoip() {
+        use tower::ServiceExt;
+
+        let source = FixedSource {
+            url: test_image("geoip"),
+        };
+        let mut state = test_state(Arc::new(source));
+        state.admin_token = Arc::new(AdminToken::new(Some("hunter2".into())));
+        let app = build_router(state, None);
+        let auth = ("authorization", "Bearer hunter2");
+
+        let (status, _, _) = get(app.clone(), "/geoip/1.2.3.4", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _, body) = get(app.clone(), "/geoip/1.2.3", &[auth]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("invalid_address"), "{body}");
+
+        // no databases in tests
+        let (status, _, _) = get(app.clone(), "/geoip/1.2.3.4", &[auth]).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let batch = |body: String| {
+            axum::http::Request::builder()
+                .method(Method::POST)
+                .uri("/geoip/batch")
+                .header("authorization", "Bearer hunter2")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body))
+                .unwrap()
+        };
+        let res = app
+            .clone()
+            .oneshot(batch(r#"["1.2.3.4", "nope"]"#.into()))
+            .await
+            .unwrap();
+        // no databases in tests either
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let ips = vec!["1.2.3.4"; GEOIP_BATCH_LIMIT + 1];
+        let res = app
+            .clone()
+            .oneshot(batch(serde_json::to_string(&ips).unwrap()))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let (status, _, _) = request(app.clone(), Method::POST, "/geoip/batch", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _, _) = request(app.clone(), Method::POST, "/admin/reload-geoip", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _, body) = request(app, Method::POST, "/admin/reload-geoip", &[auth]).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("geolocation_unavailable"), "{body}");
+
+        // without a token, single lookups are as closed as batches
+        let app = test_app("geoip");
+        let (status, _, _) = get(app.clone(), "/geoip/1.2.3.4", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _, _) = request(app, Method::POST, "/geoip/batch", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        let source = FixedSource {
+            url: test_image("maintenance"),
+        };
+        let state = test_state(Arc::new(source));
+        let maintenance = state.maintenance.clone();
+        let app = build_router(state, None);
+
+        // without an admin token, nobody gets to flip the switch
+        let (status, _, _) = request(app.clone(), Method::POST, "/admin/maintenance", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        maintenance.set_enabled(true);
+        let (status, headers, body) = get(app.clone(), "/", &[]).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(headers[header::RETRY_AFTER], "60");
+        assert!(body.contains("maintenance"), "{body}");
+        let (status, _, _) = get(app.clone(), "/healthz", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let mut state = test_state(Arc::new(FixedSource {
+            url: test_image("maintenance"),
+        }));
+        state.admin_token = Arc::new(AdminToken::new(Some("hunter2".into())));
+        let app = build_router(state, None);
+        let res = tower::ServiceExt::oneshot(
+            app.clone(),
+            axum::http::Request::builder()
+                .method(Method::POST)
+                .uri("/admin/maintenance")
+                .header(header::AUTHORIZATION, "Bearer hunter2")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(r#"{"enabled":true}"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let (status, _, _) = get(app, "/cat.txt", &[]).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn test_analytics_export() {
+        let mut state = test_state(Arc::new(FixedSource {
+            url: test_image("export"),
+        }));
+        let app = build_router(state.clone(), None);
+        let (status, _, _) = get(app, "/admin/analytics/export", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        state.admin_token = Arc::new(AdminToken::new(Some("hunter2".into())));
+        let app = build_router(state, None);
+        let (status, _, body) = get(
+            app,
+            "/admin/analytics/export",
+            &[("authorization", "Bearer hunter2")],
+        )
+        .await;
+        // there's no analytics database in tests
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body.contains("analytics"), "{body}");
+    }
+
+    /// Geolocation over `store`, with every address in 0.0.0.0/1 in France
+    fn test_geolocator(store: Arc<dyn AnalyticsStore>) -> Arc<Geolocator> {
+        Arc::new(Geolocator::new(
+            store,
+            "unused".into(),
+            geo::test_countries("FR"),
+            None,
+            0,
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_visits_counted() {
+        let store = Arc::new(MemoryStore::default());
+        let geolocator = test_geolocator(store.clone());
+        let visits = Arc::new(VisitRecorder::spawn(
+            geolocator.clone(),
+            1,
+            Duration::from_secs(60),
+        ));
+        let mut state = test_state(Arc::new(FixedSource {
+            url: test_image("visits"),
+        }));
+        state.locat = Some(geolocator);
+        state.visits = Some(visits.clone());
+        let app = build_router(state, None);
+
+        let client = [("fly-client-ip", "1.2.3.4")];
+        for uri in ["/", "/analytics", "/analytics.json", "/healthz"] {
+            let (status, _, _) = get(app.clone(), uri, &client).await;
+            assert_eq!(status, StatusCode::OK, "{uri}");
+        }
+        // waits for the queued visits to be recorded
+        visits.shutdown().await;
+        assert_eq!(store.get_analytics().await.unwrap(), [("FR".to_owned(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_blocked_countries() {
+        use tower::ServiceExt;
+
+        let mut state = test_state(Arc::new(FixedSource {
+            url: test_image("blocked"),
+        }));
+        // the test database puts 0.0.0.0/1 in FR, and nothing else anywhere
+        state.locat = Some(test_geolocator(Arc::new(MemoryStore::default())));
+        state.blocked_countries = Arc::new(["fr".to_owned()]);
+        let from = |peer: [u8; 4], headers: &[(&str, &str)]| {
+            let mut req = axum::http::Request::builder().uri("/cat.txt");
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            req.extension(ConnectInfo(SocketAddr::from((peer, 1234))))
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+        let status = |app: Router, req| async move { app.oneshot(req).await.unwrap().status() };
+
+        let app = build_router(state.clone(), None);
+        let blocked = from([1, 2, 3, 4], &[]);
+        assert_eq!(status(app.clone(), blocked).await, StatusCode::FORBIDDEN);
+        // made up addresses don't get around it
+        let spoofed = from([1, 2, 3, 4], &[("x-forwarded-for", "200.0.0.1")]);
+        assert_eq!(status(app.clone(), spoofed).await, StatusCode::FORBIDDEN);
+        let spoofed = from([1, 2, 3, 4], &[("fly-client-ip", "200.0.0.1")]);
+        assert_eq!(status(app.clone(), spoofed).await, StatusCode::FORBIDDEN);
+        let elsewhere = from([200, 0, 0, 1], &[("x-forwarded-for", "1.2.3.4")]);
+        assert_eq!(status(app, elsewhere).await, StatusCode::OK);
+
+        // behind fly, its header is the client
+        state.trust_fly_client_ip = true;
+        let app = build_router(state, None);
+        let proxied = from([200, 0, 0, 1], &[("fly-client-ip", "1.2.3.4")]);
+        assert_eq!(status(app.clone(), proxied).await, StatusCode::FORBIDDEN);
+        let proxied = from([1, 2, 3, 4], &[("fly-client-ip", "200.0.0.1")]);
+        assert_eq!(status(app, proxied).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_analytics_reset() {
+        let store = Arc::new(MemoryStore::default());
+        store
+            .record_visit(IpAddr::from([1, 2, 3, 4]), "FR")
+            .await
+            .unwrap();
+        let mut state = test_state(Arc::new(FixedSource {
+            url: test_image("reset"),
+        }));
+        state.locat = Some(test_geolocator(store.clone()));
+        state.admin_token = Arc::new(AdminToken::new(Some("hunter2".into())));
+        let app = build_router(state, None);
+
+        for auth in [&[][..], &[("authorization", "Bearer hunter3")]] {
+            let (status, _, _) = request(app.clone(), Method::POST, "/analytics/reset", auth).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(store.get_analytics().await.unwrap().len(), 1);
+        }
+
+        let auth = [("authorization", "Bearer hunter2")];
+        let (status, _, body) = request(app, Method::POST, "/analytics/reset", &auth).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(body.is_empty(), "{body}");
+        assert!(store.get_analytics().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_since() {
+        let now = humantime::parse_rfc3339("2024-01-31T12:00:00Z").unwrap();
+        let at = |time| humantime::parse_rfc3339(time).unwrap();
+        assert_eq!(parse_since("24h", now), Some(at("2024-01-30T12:00:00Z")));
+        assert_eq!(parse_since("30m", now), Some(at("2024-01-31T11:30:00Z")));
+        assert_eq!(parse_since("7days", now), Some(at("2024-01-24T12:00:00Z")));
+        let time = "2024-01-01T00:00:00Z";
+        assert_eq!(parse_since(time, now), Some(at(time)));
+        assert_eq!(
+            parse_since("2024-01-01T02:00:00+02:00", now),
+            Some(at("2024-01-01T00:00:00Z"))
+        );
+        assert_eq!(
+            parse_since("2023-12-31T19:30:00-04:30", now),
+            Some(at("2024-01-01T00:00:00Z"))
+        );
+        for since in [
+            "yesterday",
+            "24",
+            "-1h",
+            "2024-13-01T00:00:00Z",
+            "2024-01-01T00:00:00+2:000",
+            "éééééé",
+        ] {
+            assert_eq!(parse_since(since, now), None, "{since}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_analytics_since() {
+        let store = Arc::new(MemoryStore::default());
+        store
+            .record_visit(IpAddr::from([1, 2, 3, 4]), "FR")
+            .await
+            .unwrap();
+        let mut state = test_state(Arc::new(FixedSource {
+            url: test_image("since"),
+        }));
+        state.locat = Some(test_geolocator(store));
+        let app = build_router(state, None);
+
+        let (status, _, body) = get(app.clone(), "/analytics?since=1h", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "FR: 1\n");
+        let (status, _, body) = get(
+            app.clone(),
+            "/analytics.json?since=2999-01-01T00:00:00Z",
+            &[],
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "[]");
+        let (status, _, body) = get(app, "/analytics.json?since=lately", &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.contains("invalid_since"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_candidates() {
+        let path =
+            std::env::temp_dir().join(format!("catscii-{}-bad-first.png", std::process::id()));
+        std::fs::write(&path, "definitely not a png").unwrap();
+        let bad: String = reqwest::Url::from_file_path(&path).unwrap().into();
+        let source = CandidateSource {
+            urls: vec![bad.clone(), test_image("candidates")],
+        };
+        let app = build_router(test_state(Arc::new(source)), None);
+        let (status, _, _) = get(app, "/cat.txt", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // the request only fails once every candidate has
+        let source = CandidateSource {
+            urls: vec![bad.clone(), bad],
+        };
+        let app = build_router(test_state(Arc::new(source)), None);
+        let (status, _, _) = get(app, "/cat.txt", &[]).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_recent_errors() {
+        let path = std::env::temp_dir().join(format!("catscii-{}-recent.png", std::process::id()));
+        std::fs::write(&path, "definitely not a png").unwrap();
+        let mut state = test_state(Arc::new(FixedSource {
+            url: reqwest::Url::from_file_path(&path).unwrap().into(),
+        }));
+        state.admin_token = Arc::new(AdminToken::new(Some("hunter2".into())));
+        let app = build_router(state, None);
+
+        get(app.clone(), "/", &[("x-request-id", "abc-123")]).await;
+        let (status, _, _) = get(app.clone(), "/admin/errors", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _, body) =
+            get(app, "/admin/errors", &[("authorization", "Bearer hunter2")]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(r#""code":"image_load_failed""#), "{body}");
+        assert!(body.contains(r#""request_id":"abc-123""#), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_debug_config() {
+        let config = Config::from_vars(|name| match name {
+            "PORT" => Some("3000".into()),
+            "ADMIN_TOKEN" => Some("hunter2".into()),
+            _ => None,
+        })
+        .unwrap();
+        let mut state = test_state(Arc::new(FixedSource {
+            url: test_image("debug_config"),
+        }));
+        state.settings = Arc::new(config.settings);
+        state.admin_token = Arc::new(AdminToken::new(Some("hunter2".into())));
+        let app = build_router(state, None);
+
+        let (status, _, _) = get(app.clone(), "/debug/config", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _, body) =
+            get(app, "/debug/config", &[("authorization", "Bearer hunter2")]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body.contains("hunter2"), "{body}");
+        assert!(
+            body.contains(r#""PORT":{"value":"3000","default":false}"#),
+            "{body}"
+        );
+        assert!(
+            body.contains(r#""LOG_FORMAT":{"value":"json","default":true}"#),
+            "{body}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_url() {
+        let app = test_app("url");
+        let url = test_image("url");
+
+        let (status, headers, body) = get(app.clone(), "/url", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        assert_eq!(body, format!(r#"{{"url":"{url}"}}"#));
+        let (status, _, _) = get(app, "/url?animal=ferret", &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_gallery() {
+        let app = test_app("gallery");
+        let (status, headers, body) = get(app, "/gallery?count=3", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/html; charset=utf-8");
+        assert!(body.starts_with(gallery::HEADER), "{body}");
+        assert_eq!(body.matches("<pre>").count(), 3);
+        assert!(body.ends_with(gallery::FOOTER), "{body}");
+
+        // nothing's streamed until a cat made it
+        let source = FixedSource {
+            url: "file:///no/such/cat.png".into(),
+        };
+        let app = build_router(test_state(Arc::new(source)), None);
+        let (status, _, body) = get(app, "/gallery", &[("accept", "application/json")]).await;
+        assert!(!status.is_success());
+        assert!(body.starts_with(r#"{"error":"#), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_fancy() {
+        let source = Arc::new(FixedSource {
+            url: test_image("fancy"),
+        });
+
+        // test_state's fact API is down
+        let app = build_router(test_state(source.clone()), None);
+        let (status, headers, body) = get(app, "/fancy", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/html; charset=utf-8");
+        assert!(body.contains(fact::PLACEHOLDER), "{body}");
+
+        let facts = Router::new().route(
+            "/fact",
+            axum::routing::get(|| async {
+                Json(serde_json::json!({ "fact": "Cats <3 boxes", "length": 13 }))
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(facts.into_make_service()),
+        );
+        let state = ServerState {
+            cat_fact_url: format!("http://{addr}/fact").into(),
+            ..test_state(source)
+        };
+        let (status, _, body) = get(build_router(state, None), "/fancy", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("<p>Cats &lt;3 boxes</p>"), "{body}");
+        assert!(body.contains("<span"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_request_budget() {
+        let source = SlowSource {
+            url: test_image("budget"),
+            delay: Duration::from_secs(5),
+            called: Default::default(),
+        };
+        let state = ServerState {
+            request_budget: Duration::from_millis(50),
+            ..test_state(Arc::new(source))
+        };
+        let recent_errors = state.recent_errors.clone();
+        let app = build_router(state, None);
+
+        let start = Instant::now();
+        let (status, _, body) = get(app, "/", &[("accept", "application/json")]).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(body.starts_with(r#"{"error":"request_timeout""#), "{body}");
+        assert_eq!(recent_errors.list()[0].code, "request_timeout");
+    }
+
+    #[tokio::test]
+    async fn test_warm_up() {
+        let url = test_image("warm-up");
+        let state = ServerState {
+            art_cache: Arc::new(ArtCache::new(8, Duration::from_secs(300))),
+            ..test_state(Arc::new(FixedSource { url: url.clone() }))
+        };
+        warm_up(state.clone()).await;
+
+        // what `/` draws by default
+        let options = RenderOptions::default();
+        assert!(state.art_cache.get(&ArtKey { url, options }).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_root_bad_image() {
+        let path = std::env::temp_dir().join(format!("catscii-{}-bad.png", std::process::id()));
+        std::fs::write(&path, "definitely not a png").unwrap();
+        let source = FixedSource {
+            url: reqwest::Url::from_file_path(&path).unwrap().into(),
+        };
+        let app = build_router(test_state(Arc::new(source)), None);
+
+        let (status, headers, body) = get(app.clone(), "/", &[]).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let id = headers[request_id::HEADER].to_str().unwrap();
+        assert_eq!(
+            body,
+            format!("The image host served an invalid image (request ID: {id})")
+        );
+
+        let (_, headers, body) = get(
+            app,
+            "/",
+            &[("accept", "application/json"), ("x-request-id", "abc-123")],
+        )
+        .await;
+        assert_eq!(headers[request_id::HEADER], "abc-123");
+        assert!(body.contains(r#""request_id":"abc-123""#), "{body}");
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[test]
+    fn test_analytics_page() {
+        let params = |limit: Option<&str>, offset: Option<&str>| AnalyticsParams {
+            limit: limit.map(str::to_owned),
+            offset: offset.map(str::to_owned),
+            format: None,
+            since: None,
+        };
+        let counts: Vec<u32> = (0..120).collect();
+        assert_eq!(params(None, None).page(&counts), &counts[..50]);
+        assert_eq!(params(Some("10"), Some("5")).page(&counts), &counts[5..15]);
+        assert_eq!(
+            params(Some("10"), Some("115")).page(&counts),
+            &counts[115..]
+        );
+        assert!(params(None, Some("500")).page(&counts).is_empty());
+        assert_eq!(
+            params(Some("lots"), Some("-1")).page(&counts),
+            &counts[..50]
+        );
+    }
+
+    #[test]
+    fn test_lookup_batch() {
+        let ips = ["1.2.3.4", " ::1 ", "10.0.0.1", "nope"].map(str::to_owned);
+        let results = lookup_batch(ips.to_vec(), |addr| {
+            (!addr.is_loopback() && addr != IpAddr::from([10, 0, 0, 1])).then(|| "FR".into())
+        });
+        let result = |ip: &str, country: Option<&str>| BatchGeoIp {
+            ip: ip.into(),
+            country: country.map(str::to_owned),
+        };
+        assert_eq!(
+            results,
+            [
+                result("1.2.3.4", Some("FR")),
+                result(" ::1 ", None),
+                result("10.0.0.1", None),
+                result("nope", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("FR"), "FR");
+        assert_eq!(csv_field("a,b"), r#""a,b""#);
+        assert_eq!(csv_field(r#"say "hi""#), r#""say ""hi""""#);
+        assert_eq!(csv_field("=1+1"), r#""'=1+1""#);
+    }
+
+    #[test]
+    fn test_prepare_analytics_db() {
+        let dir = std::env::temp_dir().join(format!("catscii-{}-analytics", std::process::id()));
+        let path = dir.join("nested/analytics.db");
+        prepare_analytics_db(&path).unwrap();
+        assert!(path.exists());
+
+        // a directory can't go under a regular file
+        prepare_analytics_db(&path.join("analytics.db")).unwrap_err();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_is_blocked() {
+        let blocked = ["KP".to_owned(), "aq".to_owned()];
+        assert!(is_blocked(&blocked, "KP"));
+        assert!(is_blocked(&blocked, "kp"));
+        assert!(is_blocked(&blocked, "AQ"));
+        assert!(!is_blocked(&blocked, "FR"));
+        assert!(!is_blocked(&[], "KP"));
+    }
+
+    #[test]
+    fn test_client_addr() {
+        let addr = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+        assert_eq!(get_client_addr(&headers(&[])), None);
+        assert_eq!(
+            get_client_addr(&headers(&[("fly-client-ip", "1.2.3.4")])),
+            addr("1.2.3.4")
+        );
+        assert_eq!(
+            get_client_addr(&headers(&[("x-forwarded-for", "1.2.3.4, 10.0.0.1")])),
+            addr("1.2.3.4")
+        );
+        assert_eq!(
+            get_client_addr(&headers(&[("x-real-ip", "::1")])),
+            addr("::1")
+        );
+
+        // precedence
+        assert_eq!(
+            get_client_addr(&headers(&[
+                ("x-real-ip", "3.3.3.3"),
+                ("x-forwarded-for", "2.2.2.2"),
+                ("fly-client-ip", "1.1.1.1"),
+            ])),
+            addr("1.1.1.1")
+        );
+        assert_eq!(
+            get_client_addr(&headers(&[
+                ("x-real-ip", "3.3.3.3"),
+                ("x-forwarded-for", "2.2.2.2"),
+            ])),
+            addr("2.2.2.2")
+        );
+
+        // malformed entries are skipped
+        assert_eq!(
+            get_client_addr(&headers(&[("x-forwarded-for", "unknown, 2.2.2.2")])),
+            addr("2.2.2.2")
+        );
+        assert_eq!(
+            get_client_addr(&headers(&[
+                ("fly-client-ip", "garbage"),
+                ("x-real-ip", "3.3.3.3"),
+            ])),
+            addr("3.3.3.3")
+        );
+    }
+
+    #[test]
+    fn test_parse_client_addr() {
+        let addr = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+        assert_eq!(parse_client_addr(" 1.2.3.4 "), addr("1.2.3.4"));
+        assert_eq!(parse_client_addr("2001:db8::1"), addr("2001:db8::1"));
+        assert_eq!(parse_client_addr("1.2.3.4:1234"), addr("1.2.3.4"));
+        assert_eq!(parse_client_addr("[::1]:80"), addr("::1"));
+        assert_eq!(parse_client_addr("[2001:db8::1]"), addr("2001:db8::1"));
+        assert_eq!(parse_client_addr("fe80::1%eth0"), addr("fe80::1"));
+        assert_eq!(parse_client_addr("[fe80::1%25]:443"), addr("fe80::1"));
+
+        assert_eq!(parse_client_addr("unknown"), None);
+        assert_eq!(parse_client_addr("[::1"), None);
+        assert_eq!(parse_client_addr("1.2.3.4:"), addr("1.2.3.4"));
+        assert_eq!(parse_client_addr(""), None);
+
+        assert_eq!(
+            get_client_addr(&headers(&[("x-forwarded-for", "[::1]:80, 10.0.0.1")])),
+            addr("::1")
+        );
+    }
+
+    #[test]
+    fn test_trusted_client_addr() {
+        let peer = Some(IpAddr::from([10, 0, 0, 1]));
+        let fly = headers(&[("fly-client-ip", "1.2.3.4"), ("x-forwarded-for", "5.6.7.8")]);
+        assert_eq!(
+            trusted_client_addr(&fly, peer, true),
+            Some(IpAddr::from([1, 2, 3, 4]))
+        );
+        // not behind fly, where anyone can send it
+        assert_eq!(trusted_client_addr(&fly, peer, false), peer);
+        assert_eq!(trusted_client_addr(&fly, None, false), None);
+
+        // made up by the client, so ignored
+        let forwarded = headers(&[("x-forwarded-for", "5.6.7.8"), ("x-real-ip", "5.6.7.9")]);
+        assert_eq!(trusted_client_addr(&forwarded, peer, true), peer);
+        assert_eq!(trusted_client_addr(&forwarded, None, true), None);
+        let garbage = headers(&[("fly-client-ip", "garbage")]);
+        assert_eq!(trusted_client_addr(&garbage, peer, true), peer);
     }
 }
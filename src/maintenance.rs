@@ -0,0 +1,75 @@
+//! Maintenance mode, for when upstream is broken: cat routes answer 503
+//! without calling it, everything else works as usual
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::BoxBody,
+    extract::State,
+    http::{header, HeaderValue, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::error::{self, ApiError, ErrorCode};
+
+pub struct Maintenance {
+    enabled: AtomicBool,
+    /// What clients are told to wait, in `Retry-After`
+    retry_after: Duration,
+}
+
+impl Maintenance {
+    /// Always starts disabled
+    pub fn new(retry_after: Duration) -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            retry_after,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+}
+
+/// The body of `POST /admin/maintenance`, and its response
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct MaintenanceState {
+    pub enabled: bool,
+}
+
+/// Turns requests away while maintenance mode is on
+pub async fn guard<B>(
+    State(maintenance): State<Arc<Maintenance>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response<BoxBody> {
+    if !maintenance.is_enabled() {
+        return next.run(req).await;
+    }
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|h| h.to_str().ok());
+    let mut res = ApiError {
+        code: ErrorCode::Maintenance,
+        json: error::wants_json(accept),
+    }
+    .into_response();
+    res.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::from(maintenance.retry_after.as_secs()),
+    );
+    res
+}
@@ -0,0 +1,133 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use axum::{
+    body::BoxBody,
+    extract::{MatchedPath, State},
+    http::{header, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry};
+
+/// Everything we let Prometheus scrape
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    /// How long fetching, downloading and converting a cat takes
+    pub art_duration: Histogram,
+    /// Dropped spans and failed exports, by kind, see [crate::telemetry]
+    pub telemetry_errors: IntCounterVec,
+    /// Requests answered since startup, for `/status`. Not registered:
+    /// Prometheus gets the same from `requests`.
+    served: AtomicU64,
+}
+
+/// Monitoring routes, which don't count as served requests
+const UNCOUNTED_ROUTES: &[&str] = &["/status", "/healthz", "/metrics"];
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("catscii".into()), None)
+            .expect("metrics prefix should be valid");
+
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests, by route and status"),
+            &["route", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to answer HTTP requests, by route",
+            ),
+            &["route"],
+        )
+        .unwrap();
+        let art_duration = Histogram::with_opts(HistogramOpts::new(
+            "ascii_art_duration_seconds",
+            "Time taken to get a cat as ASCII art",
+        ))
+        .unwrap();
+        let telemetry_errors = IntCounterVec::new(
+            Opts::new(
+                "telemetry_errors_total",
+                "Spans dropped or not exported, by kind",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(art_duration.clone())).unwrap();
+        registry
+            .register(Box::new(telemetry_errors.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            art_duration,
+            telemetry_errors,
+            served: AtomicU64::new(0),
+        }
+    }
+
+    /// Requests answered since startup, monitoring excluded
+    pub fn served(&self) -> u64 {
+        self.served.load(Ordering::Relaxed)
+    }
+
+    /// Renders all metrics in the Prometheus text format
+    pub fn render(&self) -> Response<BoxBody> {
+        let encoder = prometheus::TextEncoder::new();
+        let mut buf = Vec::new();
+        if let Err(e) = encoder.encode(&self.registry.gather(), &mut buf) {
+            tracing::warn!("Could not encode metrics: {e}");
+        }
+        (
+            [(header::CONTENT_TYPE, encoder.format_type().to_owned())],
+            buf,
+        )
+            .into_response()
+    }
+}
+
+/// Middleware counting and timing every request to a known route
+pub async fn track<B>(
+    State(metrics): State<Arc<Metrics>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response<BoxBody> {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unknown".into());
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+
+    metrics
+        .request_duration
+        .with_label_values(&[&route])
+        .observe(start.elapsed().as_secs_f64());
+    metrics
+        .requests
+        .with_label_values(&[&route, response.status().as_str()])
+        .inc();
+    if !UNCOUNTED_ROUTES.contains(&route.as_str()) {
+        metrics.served.fetch_add(1, Ordering::Relaxed);
+    }
+    response
+}
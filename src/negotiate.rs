@@ -0,0 +1,90 @@
+/// Picks which of the `offered` media types the client would rather get,
+/// according to its `Accept` header. Offers are listed in our own order of
+/// preference, which breaks ties and decides when there's no header at all.
+///
+/// Returns `None` if the client explicitly refuses everything we offer.
+pub fn preferred<'a>(accept: Option<&str>, offered: &[&'a str]) -> Option<&'a str> {
+    let accept = match accept.map(str::trim) {
+        Some(accept) if !accept.is_empty() => accept,
+        _ => return offered.first().copied(),
+    };
+    let ranges: Vec<(&str, f32)> = accept.split(',').filter_map(parse_range).collect();
+
+    let mut best: Option<(&'a str, f32)> = None;
+    for &media in offered {
+        let q = quality(&ranges, media);
+        if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+            best = Some((media, q));
+        }
+    }
+    best.map(|(media, _)| media)
+}
+
+/// Splits `text/plain;q=0.5` into its media range and quality
+fn parse_range(range: &str) -> Option<(&str, f32)> {
+    let mut params = range.split(';');
+    let media = params.next()?.trim();
+    if media.is_empty() {
+        return None;
+    }
+    let q = params
+        .filter_map(|p| p.trim().strip_prefix("q="))
+        .find_map(|q| q.trim().parse::<f32>().ok())
+        .unwrap_or(1.0);
+    Some((media, q))
+}
+
+/// Quality the client gives `media`, using the most specific matching range
+fn quality(ranges: &[(&str, f32)], media: &str) -> f32 {
+    let (kind, _) = media.split_once('/').unwrap_or((media, ""));
+    let mut best: Option<(u8, f32)> = None;
+    for &(range, q) in ranges {
+        let specificity = if range.eq_ignore_ascii_case(media) {
+            2
+        } else if range
+            .strip_suffix("/*")
+            .map_or(false, |k| k.eq_ignore_ascii_case(kind))
+        {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        if best.map_or(true, |(s, _)| specificity > s) {
+            best = Some((specificity, q));
+        }
+    }
+    best.map_or(0.0, |(_, q)| q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFERED: &[&str] = &["text/html", "text/plain"];
+
+    #[test]
+    fn test_preferred() {
+        assert_eq!(preferred(None, OFFERED), Some("text/html"));
+        assert_eq!(preferred(Some("*/*"), OFFERED), Some("text/html"));
+        assert_eq!(preferred(Some("text/plain"), OFFERED), Some("text/plain"));
+        assert_eq!(
+            preferred(Some("text/plain, */*;q=0.8"), OFFERED),
+            Some("text/plain")
+        );
+        assert_eq!(
+            preferred(
+                Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+                OFFERED
+            ),
+            Some("text/html")
+        );
+        assert_eq!(
+            preferred(Some("text/html;q=0.5, text/plain"), OFFERED),
+            Some("text/plain")
+        );
+        assert_eq!(preferred(Some("text/*"), OFFERED), Some("text/html"));
+        assert_eq!(preferred(Some("image/png"), OFFERED), None);
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::BoxBody,
+    extract::{ConnectInfo, State},
+    http::{header, HeaderValue, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use lru::LruCache;
+
+use crate::error::{self, ApiError, ErrorCode};
+
+/// Past this many tracked clients, the least recently seen one is forgotten
+/// to make room: a client back after that long has most likely refilled its
+/// bucket anyway.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Token-bucket rate limiter, one bucket per client IP. Clients whose IP we
+/// can't determine all share the `None` bucket.
+pub struct RateLimiter {
+    /// Bucket size, and how many tokens get added back per minute. Zero
+    /// disables rate limiting.
+    per_minute: u32,
+    /// See [Config::trust_fly_client_ip](crate::config::Config)
+    trust_fly_client_ip: bool,
+    buckets: Mutex<LruCache<Option<IpAddr>, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32, trust_fly_client_ip: bool) -> Self {
+        Self::with_capacity(per_minute, trust_fly_client_ip, MAX_TRACKED_CLIENTS)
+    }
+
+    fn with_capacity(per_minute: u32, trust_fly_client_ip: bool, max_clients: usize) -> Self {
+        let max_clients = NonZeroUsize::new(max_clients).expect("the rate limiter tracks clients");
+        Self {
+            per_minute,
+            trust_fly_client_ip,
+            buckets: Mutex::new(LruCache::new(max_clients)),
+        }
+    }
+
+    /// Takes a token from `client`'s bucket, or returns how long until one
+    /// becomes available.
+    pub fn check(&self, client: Option<IpAddr>) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: Option<IpAddr>, now: Instant) -> Result<(), Duration> {
+        if self.per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = self.per_minute as f64;
+        let per_sec = capacity / 60.0;
+        let refill = |bucket: &mut Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * per_sec).min(capacity);
+            bucket.updated = now;
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_or_insert_mut(client, || Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        refill(bucket);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+        }
+    }
+}
+
+/// Middleware answering 429 to clients that ran out of tokens
+pub async fn limit<B>(
+    State(limiter): State<Arc<RateLimiter>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response<BoxBody> {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let client = crate::trusted_client_addr(req.headers(), peer, limiter.trust_fly_client_ip);
+    match limiter.check(client) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => {
+            let accept = req
+                .headers()
+                .get(header::ACCEPT)
+                .and_then(|h| h.to_str().ok());
+            let mut res = ApiError {
+                code: ErrorCode::RateLimited,
+                json: error::wants_json(accept),
+            }
+            .into_response();
+            // Retry-After is in whole seconds, round up so clients that
+            // honor it don't get limited again
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            res.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+            res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let limiter = RateLimiter::new(2, false);
+        let alice = Some(IpAddr::from([10, 0, 0, 1]));
+        let bob = Some(IpAddr::from([10, 0, 0, 2]));
+        let start = Instant::now();
+
+        assert!(limiter.check_at(alice, start).is_ok());
+        assert!(limiter.check_at(alice, start).is_ok());
+        let retry_after = limiter.check_at(alice, start).unwrap_err();
+        assert_eq!(retry_after.as_secs(), 30);
+
+        // other clients have their own bucket, unknown ones share one
+        assert!(limiter.check_at(bob, start).is_ok());
+        assert!(limiter.check_at(None, start).is_ok());
+        assert!(limiter.check_at(None, start).is_ok());
+        assert!(limiter.check_at(None, start).is_err());
+
+        // two tokens a minute means one every 30 seconds
+        assert!(limiter
+            .check_at(alice, start + Duration::from_secs(30))
+            .is_ok());
+        assert!(limiter
+            .check_at(alice, start + Duration::from_secs(30))
+            .is_err());
+    }
+
+    #[test]
+    fn test_bounded() {
+        let limiter = RateLimiter::with_capacity(1, false, 2);
+        let start = Instant::now();
+        let client = |i: u8| Some(IpAddr::from([10, 0, 0, i]));
+        assert!(limiter.check_at(client(1), start).is_ok());
+        assert!(limiter.check_at(client(1), start).is_err());
+        for i in 2..100 {
+            assert!(limiter.check_at(client(i), start).is_ok());
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+        // forgotten, so it starts over
+        assert!(limiter.check_at(client(1), start).is_ok());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let limiter = RateLimiter::new(0, false);
+        for _ in 0..100 {
+            assert!(limiter.check(None).is_ok());
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! The last few errors cats were served with, for `GET /admin/errors`: a
+//! quicker look than digging through logs or traces
+
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::error::ErrorCode;
+
+/// How many errors are kept, older ones are forgotten
+pub const CAPACITY: usize = 50;
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct RecentError {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub code: &'static str,
+    /// The whole chain, like `Could not query The Cat API: timed out`
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Default)]
+pub struct RecentErrors {
+    errors: Mutex<VecDeque<RecentError>>,
+}
+
+impl RecentErrors {
+    pub fn record(&self, code: ErrorCode, e: &color_eyre::Report, request_id: Option<String>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == CAPACITY {
+            errors.pop_front();
+        }
+        errors.push_back(RecentError {
+            timestamp,
+            code: code.as_str(),
+            message: format!("{e:#}"),
+            request_id,
+        });
+    }
+
+    /// Most recent first
+    pub fn list(&self) -> Vec<RecentError> {
+        self.errors.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounded() {
+        let recent = RecentErrors::default();
+        for i in 0..CAPACITY + 5 {
+            let e = color_eyre::eyre::eyre!("error {i}");
+            recent.record(ErrorCode::Internal, &e, None);
+        }
+        let errors = recent.list();
+        assert_eq!(errors.len(), CAPACITY);
+        assert_eq!(errors[0].message, format!("error {}", CAPACITY + 4));
+        assert_eq!(errors[CAPACITY - 1].message, "error 5");
+    }
+}
@@ -0,0 +1,97 @@
+//! Request IDs, so a failed request can be matched with its traces
+
+use std::future::Future;
+
+use axum::{
+    body::BoxBody,
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+
+pub const HEADER: &str = "x-request-id";
+
+/// Longest incoming ID we keep, anything longer gets replaced
+const MAX_LEN: usize = 128;
+
+/// Stashed in the request extensions
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+tokio::task_local! {
+    static CURRENT: RequestId;
+}
+
+/// ID of the request being handled, if called from within [propagate]
+pub fn current() -> Option<String> {
+    CURRENT.try_with(|id| id.0.clone()).ok()
+}
+
+/// Runs `f` with `id` as [current]. Task-locals don't follow
+/// `tokio::spawn`, so spawned work needs this to keep the request's ID.
+pub async fn scope<F: Future>(id: Option<String>, f: F) -> F::Output {
+    match id {
+        Some(id) => CURRENT.scope(RequestId(id), f).await,
+        None => f.await,
+    }
+}
+
+/// Reuses the client's (or proxy's) `X-Request-Id` if it looks sane,
+/// generates one otherwise
+fn from_header(value: Option<&HeaderValue>) -> RequestId {
+    let incoming = value
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_LEN);
+    RequestId(match incoming {
+        Some(id) => id.to_owned(),
+        None => uuid::Uuid::new_v4().to_string(),
+    })
+}
+
+/// Gives every request an ID, available through [current] while it's
+/// handled, and echoes it back in the response headers
+pub async fn propagate<B>(mut req: Request<B>, next: Next<B>) -> Response<BoxBody> {
+    let id = from_header(req.headers().get(HEADER));
+    req.extensions_mut().insert(id.clone());
+
+    let header = HeaderValue::from_str(&id.0).expect("request IDs are valid header values");
+    let mut res = CURRENT.scope(id, next.run(req)).await;
+    res.headers_mut().insert(HEADER, header);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_header() {
+        let id = |v: &'static str| from_header(Some(&HeaderValue::from_static(v))).0;
+        assert_eq!(id("abc-123"), "abc-123");
+        assert_eq!(id("  abc  "), "abc");
+
+        // generated
+        assert_eq!(id("").len(), 36);
+        assert_eq!(from_header(None).0.len(), 36);
+        assert_ne!(from_header(None).0, from_header(None).0);
+        let long: &'static str = Box::leak("a".repeat(MAX_LEN + 1).into_boxed_str());
+        assert_eq!(id(long).len(), 36);
+    }
+
+    #[tokio::test]
+    async fn test_scope() {
+        let lost = CURRENT.scope(RequestId("abc-123".to_owned()), async {
+            tokio::spawn(async { current() }).await.unwrap()
+        });
+        assert_eq!(lost.await, None);
+
+        let kept = CURRENT.scope(RequestId("abc-123".to_owned()), async {
+            tokio::spawn(scope(current(), async { current() }))
+                .await
+                .unwrap()
+        });
+        assert_eq!(kept.await.as_deref(), Some("abc-123"));
+        assert_eq!(scope(None, async { current() }).await, None);
+    }
+}
@@ -0,0 +1,90 @@
+//! Head-based trace sampling, so only a fraction of requests are exported to
+//! Honeycomb
+//!
+//! The Honeycomb pipeline keeps the SDK's default sampler, which is parent
+//! based: spans whose parent wasn't sampled aren't sampled either. So to drop
+//! a trace, its root span is started under a made-up, unsampled remote
+//! parent, and everything below it follows suit.
+//!
+//! This is unrelated to `$RUST_LOG`, which filters log lines: unsampled
+//! requests still log as usual, and a quiet `$RUST_LOG` doesn't drop spans.
+
+use opentelemetry::{
+    global,
+    trace::{
+        Span, SpanContext, SpanId, Status, TraceContextExt, TraceFlags, TraceId, TraceState, Tracer,
+    },
+    Context, KeyValue,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct TraceSampler {
+    /// Between 0.0 (export nothing) and 1.0 (export everything)
+    rate: f64,
+}
+
+impl TraceSampler {
+    pub fn new(rate: f64) -> Self {
+        Self {
+            rate: rate.clamp(0.0, 1.0),
+        }
+    }
+
+    /// The context to start a request's root span in. At 1.0 that's the
+    /// current context, so every trace is kept exactly like before.
+    pub fn root_context(&self) -> Context {
+        if self.rate >= 1.0 || rand::random::<f64>() < self.rate {
+            return Context::current();
+        }
+        let unsampled = SpanContext::new(
+            TraceId::from_bytes((rand::random::<u128>() | 1).to_be_bytes()),
+            SpanId::from_bytes((rand::random::<u64>() | 1).to_be_bytes()),
+            TraceFlags::default(),
+            true,
+            TraceState::default(),
+        );
+        Context::current().with_remote_span_context(unsampled)
+    }
+}
+
+/// Marks the span in `cx` as failed. Errors are always worth a trace, so if
+/// that span wasn't sampled, a lone `unsampled_error` span is exported
+/// instead: the spans that led up to the error are gone already.
+pub fn record_error(cx: &Context, description: String) {
+    let span = cx.span();
+    if span.span_context().is_sampled() {
+        span.set_status(Status::Error {
+            description: description.into(),
+        });
+        return;
+    }
+
+    let mut span = global::tracer("").start_with_context("unsampled_error", &Context::new());
+    if let Some(id) = crate::request_id::current() {
+        span.set_attribute(KeyValue::new("request_id", id));
+    }
+    span.set_status(Status::Error {
+        description: description.into(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_unsampled(cx: &Context) -> bool {
+        let span_context = cx.span().span_context().clone();
+        span_context.is_valid() && !span_context.is_sampled()
+    }
+
+    #[test]
+    fn test_root_context() {
+        for _ in 0..100 {
+            assert!(!is_unsampled(&TraceSampler::new(1.0).root_context()));
+            assert!(is_unsampled(&TraceSampler::new(0.0).root_context()));
+        }
+        // out of range rates are clamped rather than rejected
+        assert!(!is_unsampled(&TraceSampler::new(7.0).root_context()));
+        assert!(is_unsampled(&TraceSampler::new(-1.0).root_context()));
+    }
+}
@@ -0,0 +1,352 @@
+use std::{
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use color_eyre::eyre::WrapErr;
+use opentelemetry::{
+    global,
+    trace::{FutureExt, Span, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use rand::seq::SliceRandom;
+use reqwest::StatusCode;
+use tracing::warn;
+
+/// What the client asked for. Sources ignore whatever they don't support.
+#[derive(Clone, Debug, Default)]
+pub struct ImageQuery {
+    /// Picks the source, rather than being passed to it
+    pub animal: Animal,
+    /// A TheCatAPI (or TheDogAPI) breed id, like `beng`
+    pub breed: Option<String>,
+    /// Asks for the same image every time, for tests and demos. Without
+    /// one, which is the default, every request gets a random image.
+    pub seed: Option<u64>,
+}
+
+/// What kind of picture to get, picked with `?animal=`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Animal {
+    /// From `$IMAGE_SOURCE`
+    #[default]
+    Cat,
+    /// Always from TheDogAPI
+    Dog,
+}
+
+impl Animal {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cat" => Some(Self::Cat),
+            "dog" => Some(Self::Dog),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Cat => "cat",
+            Self::Dog => "dog",
+        }
+    }
+}
+
+/// Somewhere we can get image URLs from
+#[async_trait::async_trait]
+pub trait ImageSource: Send + Sync {
+    /// Returns the URL of an image to turn into ASCII art
+    async fn fetch_image_url(
+        &self,
+        client: &reqwest::Client,
+        query: &ImageQuery,
+    ) -> color_eyre::Result<String>;
+
+    /// Returns a few images to try in order, in case some can't be
+    /// downloaded. Never empty. Sources that can only give one at a time
+    /// hand out just that.
+    async fn fetch_image_urls(
+        &self,
+        client: &reqwest::Client,
+        query: &ImageQuery,
+    ) -> color_eyre::Result<Vec<String>> {
+        Ok(vec![self.fetch_image_url(client, query).await?])
+    }
+
+    /// Whether this source hands out `file://` URLs. Only then are we
+    /// allowed to read local files, so a remote API can't make us serve
+    /// arbitrary files from disk.
+    fn serves_local_files(&self) -> bool {
+        false
+    }
+}
+
+/// The source has no image matching the query
+#[derive(Debug)]
+pub struct NoImageFound;
+
+impl std::fmt::Display for NoImageFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("no image matches the query")
+    }
+}
+
+impl std::error::Error for NoImageFound {}
+
+/// Where cats come from, picked with `$IMAGE_SOURCE`
+pub enum SourceConfig {
+    TheCatApi,
+    /// From `$LOCAL_IMAGE_DIR`
+    LocalDir(PathBuf),
+}
+
+impl SourceConfig {
+    pub fn build(&self, catapi: &ApiConfig) -> Arc<dyn ImageSource> {
+        match self {
+            Self::TheCatApi => Arc::new(TheApiSource::cats(catapi)),
+            Self::LocalDir(dir) => Arc::new(LocalDirSource { dir: dir.clone() }),
+        }
+    }
+}
+
+/// Settings for TheCatAPI or TheDogAPI, from `$CATAPI_*` or `$DOGAPI_*`
+pub struct ApiConfig {
+    /// How many times to retry after the first attempt fails transiently
+    pub max_retries: u32,
+    /// Authenticated requests get higher rate limits
+    pub api_key: Option<String>,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            api_key: None,
+        }
+    }
+}
+
+/// Random pictures from TheCatAPI, or its sibling TheDogAPI: they answer
+/// the same way.
+pub struct TheApiSource {
+    /// For error messages, like "The Cat API"
+    name: &'static str,
+    api_url: String,
+    /// How many times to retry after the first attempt fails transiently
+    max_retries: u32,
+    /// Authenticated requests get higher rate limits
+    api_key: Option<String>,
+    /// The API can't be seeded, so the first image picked for a seed (and
+    /// breed) is remembered instead. Forgotten across restarts.
+    seeded: Mutex<lru::LruCache<(u64, Option<String>), String>>,
+}
+
+/// How many seeds [TheApiSource] remembers images for
+const SEEDED_CAPACITY: usize = 256;
+
+/// How many images [TheApiSource] asks for at once. The API may return
+/// fewer.
+const CANDIDATES: u32 = 3;
+
+/// Delay before the first retry, doubled for every retry after that
+const CATAPI_BASE_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(serde::Deserialize)]
+struct CatImage {
+    url: String,
+}
+
+impl TheApiSource {
+    fn new(name: &'static str, api_url: &str, config: &ApiConfig) -> Self {
+        Self {
+            name,
+            api_url: api_url.into(),
+            max_retries: config.max_retries,
+            api_key: config.api_key.clone(),
+            seeded: Mutex::new(lru::LruCache::new(
+                NonZeroUsize::new(SEEDED_CAPACITY).unwrap(),
+            )),
+        }
+    }
+
+    pub fn cats(config: &ApiConfig) -> Self {
+        Self::new(
+            "The Cat API",
+            "https://api.thecatapi.com/v1/images/search",
+            config,
+        )
+    }
+
+    pub fn dogs(config: &ApiConfig) -> Self {
+        Self::new(
+            "The Dog API",
+            "https://api.thedogapi.com/v1/images/search",
+            config,
+        )
+    }
+
+    async fn query(
+        &self,
+        client: &reqwest::Client,
+        query: &ImageQuery,
+    ) -> reqwest::Result<Vec<CatImage>> {
+        let mut req = client.get(&self.api_url);
+        if let Some(api_key) = &self.api_key {
+            req = req.header("x-api-key", api_key);
+        }
+        if let Some(breed) = &query.breed {
+            req = req.query(&[("breed_ids", breed)]);
+        }
+        req = req.query(&[("limit", CANDIDATES)]);
+        req.send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<CatImage>>()
+            .await
+    }
+}
+
+/// Connection problems, rate limiting and server errors are worth another
+/// try, anything else (bad request, undecodable body...) is not.
+fn is_retryable(e: &reqwest::Error) -> bool {
+    match e.status() {
+        Some(status) => status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error(),
+        None => e.is_connect() || e.is_timeout(),
+    }
+}
+
+#[async_trait::async_trait]
+impl ImageSource for TheApiSource {
+    async fn fetch_image_url(
+        &self,
+        client: &reqwest::Client,
+        query: &ImageQuery,
+    ) -> color_eyre::Result<String> {
+        let mut urls = self.fetch_image_urls(client, query).await?;
+        Ok(urls.swap_remove(0))
+    }
+
+    async fn fetch_image_urls(
+        &self,
+        client: &reqwest::Client,
+        query: &ImageQuery,
+    ) -> color_eyre::Result<Vec<String>> {
+        // a seed always gets the one image it got the first time
+        let seed_key = query.seed.map(|seed| (seed, query.breed.clone()));
+        if let Some(key) = &seed_key {
+            if let Some(url) = self.seeded.lock().unwrap().get(key) {
+                return Ok(vec![url.clone()]);
+            }
+        }
+
+        let tracer = global::tracer("");
+
+        let mut attempt = 0;
+        let images = loop {
+            let mut span = tracer.start("catapi_attempt");
+            span.set_attribute(KeyValue::new("attempt", attempt as i64));
+
+            match self
+                .query(client, query)
+                .with_context(Context::current_with_span(span))
+                .await
+            {
+                Ok(images) => break images,
+                Err(e) if attempt < self.max_retries && is_retryable(&e) => {
+                    let delay = CATAPI_BASE_BACKOFF * 2u32.saturating_pow(attempt);
+                    warn!("{} request failed ({e}), retrying in {delay:?}", self.name);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e).wrap_err_with(|| format!("Could not query {}", self.name)),
+            }
+        };
+
+        if images.is_empty() {
+            return Err(NoImageFound).wrap_err_with(|| format!("{} returned no images", self.name));
+        }
+        let mut urls: Vec<String> = images.into_iter().map(|image| image.url).collect();
+        if let Some(key) = seed_key {
+            urls.truncate(1);
+            self.seeded.lock().unwrap().put(key, urls[0].clone());
+        }
+        Ok(urls)
+    }
+}
+
+/// Random images from a local directory, for working offline
+pub struct LocalDirSource {
+    /// Canonicalized, see [crate::config::Config]
+    dir: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl ImageSource for LocalDirSource {
+    async fn fetch_image_url(
+        &self,
+        _client: &reqwest::Client,
+        query: &ImageQuery,
+    ) -> color_eyre::Result<String> {
+        let mut images = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir)
+            .await
+            .wrap_err_with(|| format!("Could not list {}", self.dir.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            // only keep what the `image` crate has a chance of loading
+            if image::ImageFormat::from_path(&path).is_ok() {
+                images.push(path);
+            }
+        }
+
+        let path = match query.seed {
+            // directory listings come in no particular order
+            Some(seed) if !images.is_empty() => {
+                images.sort();
+                images.get((seed % images.len() as u64) as usize)
+            }
+            _ => images.choose(&mut rand::thread_rng()),
+        };
+        let path = path
+            .ok_or(NoImageFound)
+            .wrap_err_with(|| format!("No images in {}", self.dir.display()))?;
+        let url = reqwest::Url::from_file_path(path)
+            .map_err(|_| color_eyre::eyre::eyre!("Invalid image path {}", path.display()))?;
+        Ok(url.into())
+    }
+
+    fn serves_local_files(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_local_seed() {
+        let dir = std::env::temp_dir().join(format!("catscii-{}-seeded", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["a.png", "b.png", "c.png"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let source = LocalDirSource { dir: dir.clone() };
+        let client = reqwest::Client::new();
+        let fetch = |seed| {
+            let query = ImageQuery {
+                seed: Some(seed),
+                ..Default::default()
+            };
+            let (source, client) = (&source, &client);
+            async move { source.fetch_image_url(client, &query).await.unwrap() }
+        };
+
+        assert_eq!(fetch(1).await, fetch(1).await);
+        assert!(fetch(1).await.ends_with("/b.png"));
+        assert!(fetch(5).await.ends_with("/c.png"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
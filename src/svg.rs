@@ -0,0 +1,148 @@
+//! Lays out artem's colored HTML output as an SVG grid
+
+use std::fmt::Write;
+
+use crate::art;
+
+/// Horizontal distance between characters, in pixels
+const CELL_WIDTH: f32 = 6.0;
+/// Vertical distance between lines. Artem squashes rows to make up for
+/// terminal characters being about twice as tall as they are wide, so keep
+/// that ratio.
+const CELL_HEIGHT: f32 = 14.0;
+const FONT_SIZE: f32 = 10.0;
+
+struct Cell<'a> {
+    ch: &'a str,
+    /// `RRGGBB`, absent when the art was rendered without colors
+    color: Option<&'a str>,
+}
+
+/// Converts art rendered with `TargetType::HtmlFile(_, false)` to SVG
+pub fn from_html(html: &str) -> String {
+    let rows = parse_html(html);
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+
+    let width = columns as f32 * CELL_WIDTH;
+    let height = rows.len() as f32 * CELL_HEIGHT;
+    let mut svg = String::new();
+    _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" font-family="Courier, monospace" font-size="{FONT_SIZE}">"#
+    );
+    for (row, cells) in rows.iter().enumerate() {
+        let y = (row as f32 + 1.0) * CELL_HEIGHT;
+        _ = write!(svg, r#"<text y="{y}" xml:space="preserve">"#);
+        for (column, cell) in cells.iter().enumerate() {
+            let x = column as f32 * CELL_WIDTH;
+            match cell.color {
+                Some(color) => _ = write!(svg, "<tspan x=\"{x}\" fill=\"#{color}\">"),
+                // nothing to draw, the next `x` keeps the alignment
+                None if cell.ch.trim().is_empty() => continue,
+                None => _ = write!(svg, "<tspan x=\"{x}\">"),
+            }
+            escape_into(&mut svg, cell.ch);
+            svg.push_str("</tspan>");
+        }
+        svg.push_str("</text>");
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+fn parse_html(html: &str) -> Vec<Vec<Cell<'_>>> {
+    const SPAN_START: &str = "<span style=\"color: #";
+    const SPAN_END: &str = "</span>";
+
+    let mut rest = art::html_contents(html);
+
+    let mut rows = vec![Vec::new()];
+    while let Some(ch) = rest.chars().next() {
+        if let Some(span) = rest.strip_prefix(SPAN_START) {
+            // `RRGGBB">c</span>`, where `c` may be any character, even `<`
+            if let Some((color, inner)) = span.split_once("\">") {
+                if let Some(ch) = inner.chars().next() {
+                    let after = &inner[ch.len_utf8()..];
+                    if let Some(after) = after.strip_prefix(SPAN_END) {
+                        rows.last_mut().unwrap().push(Cell {
+                            ch: &inner[..ch.len_utf8()],
+                            color: Some(color),
+                        });
+                        rest = after;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        if ch == '\n' {
+            rows.push(Vec::new());
+        } else {
+            rows.last_mut().unwrap().push(Cell {
+                ch: &rest[..ch.len_utf8()],
+                color: None,
+            });
+        }
+        rest = &rest[ch.len_utf8()..];
+    }
+
+    while rows.last().map_or(false, Vec::is_empty) {
+        rows.pop();
+    }
+    rows
+}
+
+/// Escapes `s` for XML, or HTML
+pub fn escape_into(out: &mut String, s: &str) {
+    for ch in s.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            _ => out.push(ch),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_html() {
+        let html = "<body>\n<pre><span style=\"color: #FF0000\">M</span> \n<span style=\"color: #00FF00\"><</span>\n</pre></body></html>";
+        let svg = from_html(html);
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.ends_with("</svg>"));
+        assert!(svg.contains(r##"width="12" height="28""##));
+        assert!(svg.contains(r##"<tspan x="0" fill="#FF0000">M</tspan>"##));
+        assert!(svg.contains(r##"<tspan x="0" fill="#00FF00">&lt;</tspan>"##));
+        assert_eq!(svg.matches("<text ").count(), 2);
+    }
+
+    #[test]
+    fn test_from_monochrome_html() {
+        let svg = from_html("<body>\n<pre>M k\n</pre></body></html>");
+        assert!(svg.contains(r#"<tspan x="0">M</tspan>"#), "{svg}");
+        // the space is skipped, but the next character stays in its column
+        assert!(svg.contains(r#"<tspan x="12">k</tspan>"#), "{svg}");
+        assert!(!svg.contains("fill="));
+    }
+
+    #[test]
+    fn test_from_artem() {
+        let image = image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, 128])
+        }));
+        let html = artem::convert(
+            image,
+            artem::options::OptionBuilder::new()
+                .target(artem::options::TargetType::HtmlFile(true, false))
+                .target_size(std::num::NonZeroU32::new(20).unwrap())
+                .build(),
+        );
+        let svg = from_html(&html);
+        assert!(svg.contains(r#"width="120""#), "{svg}");
+        assert!(!svg.contains("<span"));
+    }
+}
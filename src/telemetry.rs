@@ -0,0 +1,68 @@
+//! What happens when traces can't be exported. Spans go through
+//! opentelemetry's batch processor, whose queue is bounded
+//! (`$OTEL_BSP_MAX_QUEUE_SIZE`, 2048 by default): when Honeycomb can't keep
+//! up, spans are dropped rather than waited on, so requests never notice.
+//! Drops and export failures are reported here instead.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use opentelemetry::{global, trace::TraceError};
+use tracing::warn;
+
+use crate::metrics::Metrics;
+
+/// Telemetry errors come in bursts, one per dropped span: only the first of
+/// every interval is logged, the counter has the rest
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The `kind` label of `telemetry_errors_total`
+fn kind(e: &global::Error) -> &'static str {
+    match e {
+        // that's how a full (or closed) queue shows up
+        global::Error::Trace(TraceError::Other(e)) if e.to_string().starts_with("send failed") => {
+            "dropped_span"
+        }
+        global::Error::Trace(TraceError::ExportFailed(_)) => "export_failed",
+        global::Error::Trace(TraceError::ExportTimedOut(_)) => "export_timed_out",
+        _ => "other",
+    }
+}
+
+/// Replaces opentelemetry's default handler, which prints every error to
+/// stderr, with a counter and a throttled warning
+pub fn handle_errors(metrics: Arc<Metrics>) {
+    let last_logged: Mutex<Option<Instant>> = Mutex::new(None);
+    let res = global::set_error_handler(move |e| {
+        let kind = kind(&e);
+        metrics.telemetry_errors.with_label_values(&[kind]).inc();
+
+        let mut last_logged = last_logged.lock().unwrap();
+        if last_logged.map_or(true, |at| at.elapsed() >= LOG_INTERVAL) {
+            *last_logged = Some(Instant::now());
+            warn!("Telemetry error ({kind}), traces may be incomplete: {e}");
+        }
+    });
+    if let Err(e) = res {
+        warn!("Could not set the telemetry error handler: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind() {
+        // what the batch processor's channel says when it's full
+        let full = TraceError::from("send failed because channel is full");
+        assert_eq!(kind(&full.into()), "dropped_span");
+        assert_eq!(
+            kind(&TraceError::ExportTimedOut(Duration::from_secs(1)).into()),
+            "export_timed_out"
+        );
+        assert_eq!(kind(&TraceError::from("oops").into()), "other");
+    }
+}
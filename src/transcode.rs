@@ -0,0 +1,86 @@
+//! The cat picture itself rather than its art, re-encoded with `?as=`
+
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageOutputFormat};
+
+/// Formats we have encoders for. Not AVIF: that needs `image`'s
+/// `avif-encoder` feature, which builds rav1e and is a lot of build for one
+/// query parameter. `?as=avif` is a 400 like any other unknown format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+}
+
+/// What JPEGs are encoded at, out of 100
+const JPEG_QUALITY: u8 = 85;
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "png" => Some(Self::Png),
+            "jpeg" | "jpg" => Some(Self::Jpeg),
+            "gif" => Some(Self::Gif),
+            "webp" => Some(Self::Webp),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpeg",
+            Self::Gif => "gif",
+            Self::Webp => "webp",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Gif => "image/gif",
+            Self::Webp => "image/webp",
+        }
+    }
+
+    pub fn encode(self, image: &DynamicImage) -> image::ImageResult<Vec<u8>> {
+        let mut bytes = Cursor::new(Vec::new());
+        match self {
+            Self::Png => image.write_to(&mut bytes, ImageOutputFormat::Png)?,
+            // JPEG has no alpha channel
+            Self::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+                .write_to(&mut bytes, ImageOutputFormat::Jpeg(JPEG_QUALITY))?,
+            Self::Gif => image.write_to(&mut bytes, ImageOutputFormat::Gif)?,
+            Self::Webp => image.write_to(&mut bytes, ImageOutputFormat::WebP)?,
+        }
+        Ok(bytes.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            8,
+            8,
+            image::Rgba([200, 120, 40, 255]),
+        ));
+        for encoding in [Encoding::Png, Encoding::Jpeg, Encoding::Gif, Encoding::Webp] {
+            let bytes = encoding.encode(&image).unwrap();
+            let format = image::guess_format(&bytes).unwrap();
+            assert_eq!(
+                image::ImageFormat::from_mime_type(encoding.content_type()),
+                Some(format),
+                "{encoding:?}"
+            );
+        }
+        assert_eq!(Encoding::from_name("avif"), None);
+    }
+}
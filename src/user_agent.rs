@@ -0,0 +1,85 @@
+//! Keeps `User-Agent` span attributes cheap: bounded in length, plus a
+//! coarse family to group by.
+
+/// Longer user agents get cut, they're nearly always junk anyway
+pub const MAX_LEN: usize = 256;
+
+/// Cuts `user_agent` to at most [MAX_LEN] bytes, on a char boundary
+pub fn truncate(user_agent: &str) -> &str {
+    if user_agent.len() <= MAX_LEN {
+        return user_agent;
+    }
+    let mut end = MAX_LEN;
+    while !user_agent.is_char_boundary(end) {
+        end -= 1;
+    }
+    &user_agent[..end]
+}
+
+/// Substrings that give a crawler away. Checked before browsers, since
+/// many crawlers claim to be Mozilla.
+const BOT_MARKERS: &[&str] = &["bot", "spider", "crawl", "slurp", "facebookexternalhit"];
+
+/// Prefixes of HTTP clients and libraries
+const CLI_PREFIXES: &[&str] = &[
+    "curl/",
+    "wget/",
+    "httpie/",
+    "xh/",
+    "python-requests/",
+    "python-urllib/",
+    "go-http-client/",
+    "libwww-perl/",
+    "node-fetch",
+    "okhttp/",
+    "reqwest",
+];
+
+/// What kind of client sent the request, roughly
+pub fn family(user_agent: &str) -> &'static str {
+    let user_agent = user_agent.to_ascii_lowercase();
+    if BOT_MARKERS.iter().any(|marker| user_agent.contains(marker)) {
+        "bot"
+    } else if CLI_PREFIXES
+        .iter()
+        .any(|prefix| user_agent.starts_with(prefix))
+    {
+        "cli"
+    } else if user_agent.starts_with("mozilla/") {
+        "browser"
+    } else {
+        "unknown"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("curl/8.0"), "curl/8.0");
+        let long = "a".repeat(1000);
+        assert_eq!(truncate(&long).len(), MAX_LEN);
+        // never splits a character in two
+        let long = format!("{}é", "a".repeat(MAX_LEN - 1));
+        assert_eq!(truncate(&long).len(), MAX_LEN - 1);
+    }
+
+    #[test]
+    fn test_family() {
+        assert_eq!(
+            family("Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/112.0"),
+            "browser"
+        );
+        assert_eq!(
+            family("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"),
+            "bot"
+        );
+        assert_eq!(family("curl/7.88.1"), "cli");
+        assert_eq!(family("Wget/1.21"), "cli");
+        assert_eq!(family("python-requests/2.28.2"), "cli");
+        assert_eq!(family(""), "unknown");
+        assert_eq!(family("my-toaster"), "unknown");
+    }
+}